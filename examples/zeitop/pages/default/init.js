@@ -145,7 +145,7 @@ setInterval(() => {
     update();
 }, 1000);
 
-z.subscribe("mpd-events", (subsystem) => {
+z.subscribe("mpd", (subsystem) => {
     dbg(mpd_status);
     update_music_state();
     z.request("mpd", "currentsong", (currentsong) => {
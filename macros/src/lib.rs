@@ -0,0 +1,188 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    Error, Expr, FnArg, ImplItem, ImplItemFn, ItemImpl, Lit, LitStr, Meta, Pat, Path, Result, Type,
+    parse_macro_input, parse_quote,
+};
+
+/// Turns the `async fn`s taking `&self` of an impl block into the commands of a request service.
+///
+/// ```ignore
+/// /// Volume of the default sink
+/// #[zeitop::service(name = "pulse")]
+/// impl PulseService {
+///     /// Set the sink volume
+///     async fn vol_set_sink(&self, volume: u32) -> Result<bool> { .. }
+/// }
+///
+/// PulseService::builder().connect().await?.serve_handler(PulseService).await
+/// ```
+///
+/// `vol_set_sink 80` calls the method with its arguments parsed in order, the returned value is sent as json
+/// and errors as error replies. Doc comments describe the service and its commands in the schema.
+/// `description = ".."` overrides the impl's doc comment, `crate = path` points at zeitop when it is renamed.
+/// Associated functions without `self` are left alone, any other method is a compile error.
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut name: Option<LitStr> = None;
+    let mut description: Option<LitStr> = None;
+    let mut krate: Path = parse_quote!(::zeitop);
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("description") {
+            description = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("crate") {
+            krate = meta.value()?.parse()?;
+        } else {
+            return Err(meta.error("expected `name`, `description` or `crate`"));
+        }
+        Ok(())
+    });
+    parse_macro_input!(attr with parser);
+    let item = parse_macro_input!(item as ItemImpl);
+    let Some(name) = name else {
+        return Error::new_spanned(&item.self_ty, "missing `name = \"..\"`")
+            .to_compile_error()
+            .into();
+    };
+    let description = description
+        .map(|d| d.value())
+        .unwrap_or_else(|| docs(&item.attrs));
+    // keep the impl block around so the error isn't buried under ones about missing methods
+    expand(&item, &name, &description, &krate)
+        .unwrap_or_else(|e| {
+            let mut tokens = e.into_compile_error();
+            tokens.extend(quote!(#item));
+            tokens
+        })
+        .into()
+}
+
+struct Command<'a> {
+    method: &'a ImplItemFn,
+    args: Vec<(syn::Ident, &'a Type)>,
+}
+
+fn expand(item: &ItemImpl, name: &LitStr, description: &str, krate: &Path) -> Result<TokenStream2> {
+    let commands = item
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            ImplItem::Fn(method) => match method.sig.inputs.first() {
+                Some(FnArg::Receiver(r))
+                    if method.sig.asyncness.is_some() && r.reference.is_some() && r.mutability.is_none() =>
+                {
+                    Some(command(method))
+                }
+                // a typo'd command shouldn't just vanish from the service
+                Some(FnArg::Receiver(_)) => Some(Err(Error::new_spanned(
+                    &method.sig,
+                    "commands are `async fn`s taking `&self`, put other methods in a separate impl block",
+                ))),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let self_ty = &item.self_ty;
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    let enum_name = match &**self_ty {
+        Type::Path(path) => match path.path.segments.last() {
+            Some(segment) => format_ident!("__{}Command", segment.ident),
+            None => format_ident!("__ServiceCommand"),
+        },
+        _ => format_ident!("__ServiceCommand"),
+    };
+    let private = quote!(#krate::__private);
+    let serde_path = LitStr::new(&quote!(#private::serde).to_string(), name.span());
+
+    let variants = commands.iter().map(|command| {
+        let ident = &command.method.sig.ident;
+        match command.args.is_empty() {
+            true => quote!(#ident),
+            false => {
+                let fields = command.args.iter().map(|(arg, ty)| quote!(#arg: #ty));
+                quote!(#ident { #(#fields),* })
+            }
+        }
+    });
+    let arms = commands.iter().map(|command| {
+        let ident = &command.method.sig.ident;
+        let args: Vec<_> = command.args.iter().map(|(arg, _)| arg).collect();
+        quote! {
+            #enum_name::#ident { #(#args),* } => {
+                let result = self.#ident(#(#args),*).await.map_err(::core::convert::Into::into);
+                let _ = req.respond(result).await;
+            }
+        }
+    });
+    let describe = commands.iter().filter_map(|command| {
+        let doc = docs(&command.method.attrs);
+        let ident = command.method.sig.ident.to_string();
+        (!doc.is_empty()).then(|| quote!(.describe(#ident, #doc)))
+    });
+
+    Ok(quote! {
+        #item
+
+        #[doc(hidden)]
+        #[allow(non_camel_case_types)]
+        #[derive(#private::serde::Deserialize)]
+        #[serde(crate = #serde_path)]
+        pub enum #enum_name {
+            #(#variants,)*
+        }
+
+        impl #impl_generics #private::ServiceHandler for #self_ty #where_clause {
+            type Command = #enum_name;
+            const NAME: &'static str = #name;
+            fn schema() -> #private::Schema {
+                #private::Schema::of::<#enum_name>(#description) #(#describe)*
+            }
+            async fn dispatch(self: ::std::sync::Arc<Self>, command: #enum_name, req: #private::Request) {
+                match command {
+                    #(#arms)*
+                }
+            }
+        }
+    })
+}
+
+fn command(method: &ImplItemFn) -> Result<Command<'_>> {
+    let args = method
+        .sig
+        .inputs
+        .iter()
+        .skip(1)
+        .map(|arg| match arg {
+            FnArg::Typed(arg) => match &*arg.pat {
+                Pat::Ident(pat) => Ok((pat.ident.clone(), &*arg.ty)),
+                pat => Err(Error::new_spanned(pat, "command arguments need a plain name")),
+            },
+            FnArg::Receiver(receiver) => Err(Error::new_spanned(receiver, "unexpected receiver")),
+        })
+        .collect::<Result<_>>()?;
+    Ok(Command { method, args })
+}
+
+/// `///` lines joined with spaces.
+fn docs(attrs: &[syn::Attribute]) -> String {
+    attrs
+        .iter()
+        .filter_map(|attr| match &attr.meta {
+            Meta::NameValue(meta) if meta.path.is_ident("doc") => match &meta.value {
+                Expr::Lit(expr) => match &expr.lit {
+                    Lit::Str(doc) => Some(doc.value().trim().to_string()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        })
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
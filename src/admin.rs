@@ -0,0 +1,132 @@
+use crate::client::ClientMapExt;
+use crate::device::Serial;
+use crate::server::{Connection, ConnectionIO, ConnectionMap};
+use crate::schema::Schema;
+use crate::service::{Service, ServiceMapExt, ServiceType};
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::to_string;
+use std::net::SocketAddr;
+use tungstenite::Message;
+
+#[derive(Serialize, Deserialize)]
+pub struct ClientEntry {
+    pub serial: Serial,
+    pub id: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ServiceEntry {
+    pub name: String,
+    pub service_type: ServiceType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<Schema>,
+    /// Connections registered under the name, more than one for a pool.
+    #[serde(default)]
+    pub instances: usize,
+}
+
+impl ServiceEntry {
+    /// One entry per name, in the order the names first show up.
+    pub fn group(services: Vec<Service>) -> Vec<Self> {
+        let mut entries: Vec<Self> = Vec::new();
+        for service in services {
+            match entries.iter_mut().find(|entry| entry.name == service.name) {
+                Some(entry) => entry.instances += 1,
+                None => entries.push(Self {
+                    name: service.name,
+                    service_type: service.service_type,
+                    schema: service.schema,
+                    instances: 1,
+                }),
+            }
+        }
+        entries
+    }
+}
+
+/// Server side of `zeitop ctl`, speaks `$admin` then one command per message.
+/// Only peers on the same machine get in, the port may be open to the whole network.
+pub struct AdminHandler {
+    connection: Connection,
+    connection_map: ConnectionMap,
+}
+
+impl AdminHandler {
+    pub fn from_req(
+        req: &str,
+        peer: SocketAddr,
+        connection: &Connection,
+        connection_map: &ConnectionMap,
+    ) -> Result<Option<Self>> {
+        if req != "$admin" {
+            return Ok(None);
+        }
+        if !peer.ip().is_loopback() {
+            let _ = connection.send(Message::text("!Admin Only From Localhost"));
+            return Err(Error::msg(format!("Admin => Refused :: {peer}")));
+        }
+        let _ = connection.send(Message::text("@Ok"));
+        Ok(Some(Self {
+            connection: connection.clone(),
+            connection_map: connection_map.clone(),
+        }))
+    }
+    pub async fn handle(&mut self) -> Result<()> {
+        match self.read().await {
+            Ok(Message::Close(_)) => Err(Error::msg("Connection closed")),
+            Ok(Message::Text(req)) => {
+                let reply = match self.command(req.as_str()).await {
+                    Ok(reply) => reply,
+                    Err(e) => format!("!{e}"),
+                };
+                self.send(reply.into())
+            }
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+    async fn command(&self, req: &str) -> Result<String> {
+        let mut args = req.split_whitespace();
+        match args.next() {
+            Some("clients") => {
+                let clients: Vec<ClientEntry> = self
+                    .connection_map
+                    .client_map
+                    .list()
+                    .await
+                    .into_iter()
+                    .map(|(serial, id)| ClientEntry { serial, id })
+                    .collect();
+                Ok(to_string(&clients)?)
+            }
+            Some("services") => {
+                let services = ServiceEntry::group(self.connection_map.service_map.list().await);
+                Ok(to_string(&services)?)
+            }
+            Some("kick") => {
+                let (Some(serial), Some(Ok(id))) = (args.next(), args.next().map(|i| i.parse::<u32>()))
+                else {
+                    return Err(Error::msg("usage: kick <serial> <id>"));
+                };
+                let Some(client) = self.connection_map.client_map.get(serial, id).await else {
+                    return Err(Error::msg("Invalid Client"));
+                };
+                client.close("Kicked");
+                println!("Admin => Kick :: {serial}@{id}");
+                Ok(String::from("true"))
+            }
+            Some(cmd) => Err(Error::msg(format!("Invalid Command {cmd}"))),
+            None => Err(Error::msg("Command Unspecified")),
+        }
+    }
+}
+
+impl ConnectionIO for AdminHandler {
+    async fn read(&mut self) -> Result<Message> {
+        self.connection.read().await
+    }
+    fn send(&self, msg: Message) -> Result<()> {
+        self.connection.send(msg)
+    }
+}
@@ -0,0 +1,120 @@
+use crate::config::Config;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use os_path::OsPath;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Use android phone as a desktop clock and more.")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the daemon (default when no subcommand is given)
+    Serve(ServeArgs),
+    /// Inspect and poke a running daemon
+    Ctl(CtlArgs),
+}
+
+#[derive(Args, Debug, Default)]
+pub struct ServeArgs {
+    /// Config file to read and watch [default: $XDG_CONFIG_HOME/zeitop/config.toml]
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<String>,
+    /// Port to listen on, overrides [server] port
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Address to bind to, overrides [server] address
+    #[arg(short, long, value_name = "ADDRESS")]
+    pub bind: Option<String>,
+    /// Default services to enable, overrides their `enabled` key
+    #[arg(long, value_name = "SERVICE", value_delimiter = ',')]
+    pub enable: Vec<DefaultServiceName>,
+    /// Default services to disable, overrides their `enabled` key
+    #[arg(long, value_name = "SERVICE", value_delimiter = ',')]
+    pub disable: Vec<DefaultServiceName>,
+    /// Don't touch adb at all, phones have to reach the server on their own
+    #[arg(long)]
+    pub no_device_handler: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct CtlArgs {
+    /// Server to talk to [default: $ZEITOP_URL, then [server] in config.toml]
+    #[arg(short, long)]
+    pub url: Option<String>,
+    /// Config file used to find the server when --url isn't given
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<String>,
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CtlCommand {
+    /// List connected clients
+    Clients,
+    /// List registered services
+    Services,
+    /// Send a request as if from a client and print the reply
+    Request {
+        service: String,
+        payload: Vec<String>,
+        /// Seconds to wait for the reply
+        #[arg(short, long, default_value_t = 5)]
+        timeout: u64,
+    },
+    /// Print every message a service broadcasts, or those whose topic matches `topic`
+    Tail { service: String, topic: Option<String> },
+    /// Disconnect a client
+    Kick { serial: String, id: u32 },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum DefaultServiceName {
+    Lib,
+    Page,
+    Sysinfo,
+    Mpd,
+    Obs,
+    Pulse,
+}
+
+impl ServeArgs {
+    pub fn config_path(&self) -> OsPath {
+        self.config
+            .as_ref()
+            .map(OsPath::from)
+            .unwrap_or_else(Config::path)
+    }
+    /// Flags always win over config.toml, including after a reload.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(port) = self.port {
+            config.server.port = port;
+        }
+        if let Some(address) = &self.bind {
+            config.server.address = address.clone();
+        }
+        for service in &self.enable {
+            service.set_enabled(config, true);
+        }
+        for service in &self.disable {
+            service.set_enabled(config, false);
+        }
+    }
+}
+
+impl DefaultServiceName {
+    fn set_enabled(self, config: &mut Config, enabled: bool) {
+        let services = &mut config.services;
+        match self {
+            Self::Lib => services.lib.enabled = enabled,
+            Self::Page => services.page.enabled = enabled,
+            Self::Sysinfo => services.sysinfo.enabled = enabled,
+            Self::Mpd => services.mpd.enabled = enabled,
+            Self::Obs => services.obs.enabled = enabled,
+            Self::Pulse => services.pulse.enabled = enabled,
+        }
+    }
+}
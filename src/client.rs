@@ -1,20 +1,17 @@
+use crate::admin::ServiceEntry;
 use crate::device::Serial;
+use crate::frame::{self, Payload};
+use crate::pending::{Pending, PendingMapExt};
+use crate::protocol::{Answer, Envelope, Kind, Protocol, legacy_tag};
 use crate::server::{Connection, ConnectionIO, ConnectionMap};
-use crate::service::{BroadcastHandler, ServiceMapExt, ServiceType};
+use crate::service::{SERVICES, Service, ServiceMapExt, ServiceType, UNSUBSCRIBE};
 use anyhow::{Error, Result};
-use futures::{
-    prelude::stream::{SplitSink, SplitStream},
-    Sink, SinkExt, Stream, StreamExt,
-};
 use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
-use tokio::net::TcpStream;
-use tokio::sync::broadcast;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tokio::task::spawn_blocking;
-use tokio::time::interval;
-use tokio_tungstenite::WebSocketStream;
+use tokio::time::Instant;
+use serde_json::to_string;
 use tungstenite::Message;
 
 pub type ClientMap = Arc<RwLock<HashMap<Serial, BTreeMap<u32, Client>>>>;
@@ -23,6 +20,7 @@ pub trait ClientMapExt {
     async fn insert(&self, serial: impl Into<Serial>, client: Client) -> Result<u32>;
     async fn get(&self, serial: impl Into<Serial>, id: u32) -> Option<Client>;
     async fn remove(&self, serial: impl Into<Serial>, id: u32) -> Result<()>;
+    async fn list(&self) -> Vec<(Serial, u32)>;
 }
 
 impl ClientMapExt for ClientMap {
@@ -48,7 +46,7 @@ impl ClientMapExt for ClientMap {
     async fn get(&self, serial: impl Into<Serial>, id: u32) -> Option<Client> {
         let serial = serial.into();
         let this = self.clone();
-        if let Ok(client) = spawn_blocking(move || {
+        spawn_blocking(move || {
             if let Some(clients) = this.read().unwrap().get(&serial) {
                 clients.get(&id).cloned()
             } else {
@@ -56,11 +54,7 @@ impl ClientMapExt for ClientMap {
             }
         })
         .await
-        {
-            client
-        } else {
-            None
-        }
+        .unwrap_or_default()
     }
     async fn remove(&self, serial: impl Into<String>, id: u32) -> Result<()> {
         let serial = serial.into();
@@ -69,7 +63,7 @@ impl ClientMapExt for ClientMap {
             let mut that = this.write().unwrap();
             if let Some(clients) = that.get_mut(&serial) {
                 clients.remove(&id);
-                if clients.len() < 1 {
+                if clients.is_empty() {
                     that.remove(&serial);
                 }
                 Ok(())
@@ -79,14 +73,106 @@ impl ClientMapExt for ClientMap {
         })
         .await?
     }
+    async fn list(&self) -> Vec<(Serial, u32)> {
+        let this = self.clone();
+        spawn_blocking(move || {
+            this.read()
+                .unwrap()
+                .iter()
+                .flat_map(|(serial, clients)| clients.keys().map(|id| (serial.clone(), *id)))
+                .collect()
+        })
+        .await
+        .unwrap_or_default()
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Client {
-    serial: Serial,
+    pub(crate) serial: Serial,
+    protocol: Protocol,
     connection: Connection,
 }
 
+impl Client {
+    /// Sends a service's answer to one of this client's requests.
+    pub fn reply(&self, pending: &Pending, answer: Answer) -> Result<()> {
+        match self.protocol {
+            Protocol::Legacy => {
+                let header = format!(
+                    "{}{}@{}",
+                    pending.request,
+                    legacy_tag(pending.tag.as_deref()),
+                    pending.service
+                );
+                self.send(match answer {
+                    Answer::Reply(payload) => frame::join(&header, payload),
+                    Answer::Error(e) => frame::join(&header, Payload::Text(format!("!{e}"))),
+                    Answer::Chunk(payload) => frame::join(&format!(">{header}"), payload),
+                    Answer::End => Message::text(format!(">{header}")),
+                })
+            }
+            Protocol::Json => {
+                let envelope = Envelope {
+                    service: Some(pending.service.clone()),
+                    id: pending.id,
+                    tag: pending.tag.clone(),
+                    ..Envelope::new(Kind::Reply)
+                };
+                self.send(
+                    match answer {
+                        Answer::Reply(payload) => envelope.with_payload(payload),
+                        Answer::Error(e) => Envelope {
+                            kind: Kind::Error,
+                            error: Some(e),
+                            ..envelope
+                        },
+                        Answer::Chunk(payload) => Envelope {
+                            kind: Kind::Chunk,
+                            ..envelope
+                        }
+                        .with_payload(payload),
+                        Answer::End => Envelope {
+                            kind: Kind::End,
+                            ..envelope
+                        },
+                    }
+                    .encode(),
+                )
+            }
+        }
+    }
+    pub fn broadcast(&self, service: &str, tag: Option<&str>, topic: Option<&str>, payload: Payload) -> Result<()> {
+        match self.protocol {
+            Protocol::Legacy => {
+                let topic = topic.map(|t| format!("@{t}")).unwrap_or_default();
+                self.send(frame::join(&format!("{service}{}{topic}", legacy_tag(tag)), payload))
+            }
+            Protocol::Json => self.send(
+                Envelope {
+                    service: Some(String::from(service)),
+                    tag: tag.map(String::from),
+                    topic: topic.map(String::from),
+                    ..Envelope::new(Kind::Broadcast)
+                }
+                .with_payload(payload)
+                .encode(),
+            ),
+        }
+    }
+    pub fn error(&self, id: Option<u64>, e: impl Into<String>) -> Result<()> {
+        self.send(self.protocol.error(id, e))
+    }
+    /// Resolves once everything sent so far has been written to the socket.
+    pub async fn flushed(&self) {
+        self.connection.flushed().await
+    }
+    /// Hangs up on the client, its handler cleans up after it.
+    pub fn close(&self, reason: &str) {
+        self.connection.close(reason)
+    }
+}
+
 impl ConnectionIO for Client {
     async fn read(&mut self) -> Result<Message> {
         self.connection.read().await
@@ -96,6 +182,102 @@ impl ConnectionIO for Client {
     }
 }
 
+/// What a client asked for, whichever protocol it speaks.
+struct ClientRequest {
+    service: String,
+    tag: Option<String>,
+    id: Option<u64>,
+    timeout: Option<Duration>,
+    /// Topic pattern of a subscription.
+    topic: Option<String>,
+    action: Action,
+}
+
+enum Action {
+    Request(Payload),
+    Subscribe,
+    /// Ends the subscription to `service` with `tag`, or every one whose tag `tag` owns without a service.
+    Unsubscribe,
+    /// Drops the client's outstanding requests with `id`, or with `service` and `tag` without one.
+    Cancel,
+}
+
+impl ClientRequest {
+    fn parse(msg: &Message, protocol: Protocol) -> Result<Self> {
+        let request = match protocol {
+            // &service#tag::payload, *service#tag::pattern, ~service#tag or -service#tag
+            Protocol::Legacy => {
+                let Some((header, payload)) = frame::split(msg) else {
+                    return Err(Error::msg("Invalid Request"));
+                };
+                // a subscription's payload is its topic pattern
+                let topic = match &payload {
+                    Some(Payload::Text(pattern)) if !pattern.is_empty() => Some(pattern.clone()),
+                    _ => None,
+                };
+                let (action, service_req) = if let Some(service_req) = header.strip_prefix("&") {
+                    (payload.map(Action::Request).unwrap_or(Action::Subscribe), service_req)
+                } else if let Some(service_req) = header.strip_prefix("*") {
+                    (Action::Subscribe, service_req)
+                } else if let Some(service_req) = header.strip_prefix("~") {
+                    (Action::Cancel, service_req)
+                } else if let Some(service_req) = header.strip_prefix("-") {
+                    (Action::Unsubscribe, service_req)
+                } else {
+                    return Err(Error::msg("Invalid Request"));
+                };
+                let (service, tag) = service_req
+                    .split_once("#")
+                    .map(|(s, t)| (s, Some(String::from(t))))
+                    .unwrap_or((service_req, None));
+                // `@` already splits the reply and broadcast headers, a version would make them ambiguous
+                if service.contains('@') {
+                    return Err(Error::msg("Versioned Services Need Json"));
+                }
+                Self {
+                    service: String::from(service),
+                    tag,
+                    id: None,
+                    timeout: None,
+                    topic,
+                    action,
+                }
+            }
+            Protocol::Json => {
+                let mut envelope = Envelope::decode(msg)?;
+                let action = match envelope.kind {
+                    Kind::Request => Action::Request(
+                        envelope
+                            .take_payload()
+                            .unwrap_or(Payload::Text(String::new())),
+                    ),
+                    Kind::Subscribe => Action::Subscribe,
+                    Kind::Unsubscribe => Action::Unsubscribe,
+                    Kind::Cancel => Action::Cancel,
+                    _ => return Err(Error::msg("Invalid Request")),
+                };
+                Self {
+                    service: envelope.service.unwrap_or_default(),
+                    tag: envelope.tag,
+                    id: envelope.id,
+                    timeout: envelope.timeout.map(Duration::from_millis),
+                    topic: envelope.topic,
+                    action,
+                }
+            }
+        };
+        let unscoped = match request.action {
+            Action::Cancel => request.id.is_some(),
+            Action::Unsubscribe => request.tag.as_deref().is_some_and(|tag| !tag.is_empty()),
+            _ => false,
+        };
+        if request.service.is_empty() && !unscoped {
+            return Err(Error::msg("Service Name Unspecified"));
+        }
+        Ok(request)
+    }
+}
+
 pub struct ClientHandler {
     client: Client,
     id: u32,
@@ -114,95 +296,128 @@ impl ClientHandler {
             connection_map,
         })
     }
+    /// A bare serial for legacy clients, a `hello` envelope for json ones.
     pub async fn from_req(
         req: &str,
         connection: &Connection,
         connection_map: &ConnectionMap,
     ) -> Result<Self> {
+        let protocol = Protocol::detect(req);
+        let serial = match protocol {
+            Protocol::Legacy => String::from(req),
+            Protocol::Json => match Envelope::handshake(req, Kind::Hello)
+                .unwrap_or_else(|| Err(Error::msg("Invalid Handshake")))
+                .and_then(|hello| hello.serial.ok_or_else(|| Error::msg("Serial Unspecified")))
+            {
+                Ok(serial) => serial,
+                Err(e) => {
+                    let _ = connection.send(protocol.error(None, e.to_string()));
+                    return Err(e);
+                }
+            },
+        };
         let client = Client {
-            serial: String::from(req),
+            serial,
+            protocol,
             connection: connection.clone(),
         };
-        let _ = client.send(Message::text("@Ok"));
+        let _ = client.send(protocol.ok());
         Self::new(client, connection_map.clone()).await
     }
+    /// Drops the requests, subscriptions and map entry the client leaves behind, however it went away.
+    pub async fn teardown(&self) {
+        // nobody is left to hear those replies
+        let (serial, id) = (self.client.serial.clone(), self.id);
+        self.connection_map
+            .pending
+            .drain(move |p| p.serial == serial && p.client == id)
+            .await;
+        for service in self.connection_map.service_map.list().await {
+            if let Some(hub) = service.hub {
+                hub.leave(&self.client.serial, self.id);
+            }
+        }
+        let _ = self
+            .connection_map
+            .client_map
+            .remove(&self.client.serial, self.id)
+            .await;
+    }
     pub async fn handle(&mut self) -> Result<()> {
         match self.read().await {
-            Ok(Message::Close(_f)) => {
-                if self
-                    .connection_map
-                    .client_map
-                    .remove(&self.client.serial, self.id)
-                    .await
-                    .is_ok()
-                {
-                    Err(Error::msg("Connection closed"))
-                } else {
-                    Err(Error::msg("Connection should close but not"))
+            Ok(Message::Close(_f)) => Err(Error::msg("Connection closed")),
+            Ok(msg @ (Message::Text(_) | Message::Binary(_))) => {
+                if let Message::Text(req) = &msg {
+                    println!("{req}");
                 }
-            }
-            Ok(Message::Text(req)) => {
-                println!("{req}");
-                if let Some(service_req) = req.strip_prefix("&") {
-                    let mut req = service_req.splitn(2, "::");
-                    let Some((service_name, tag)) = req.next().map(|r| {
-                        r.split_once("#")
-                            .map(|(s, t)| (s, format!("#{t}")))
-                            .unwrap_or((r, String::new()))
-                    }) else {
-                        let _ = self.send("!Service Name Unspecified".into());
-                        return Ok(());
-                    };
-                    let Ok(service) = self.connection_map.service_map.get(service_name).await
-                    else {
-                        let _ = self.send("!Invalid Service".into());
+                let req = match ClientRequest::parse(&msg, self.client.protocol) {
+                    Ok(req) => req,
+                    Err(e) => {
+                        let _ = self.client.error(None, e.to_string());
                         return Ok(());
-                    };
-                    match service.service_type {
-                        ServiceType::Request => {
-                            if let Some(req) = req.next() {
-                                if service
-                                    .send(
-                                        format!(
-                                            "{}@{}{}::{}",
-                                            self.client.serial, self.id, tag, req
-                                        )
-                                        .into(),
-                                    )
-                                    .is_err()
-                                {
-                                    return Err(Error::msg("Can not Send"));
-                                }
-                            }
-                        }
-                        ServiceType::Broadcast => {
-                            let Ok(mut handler) = BroadcastHandler::new(
-                                service,
-                                tag,
-                                self.client.clone(),
-                                self.connection_map.clone(),
-                            ) else {
-                                let _ = self.send("!Not a Broadcast Service".into());
-                                return Ok(());
-                            };
-                            tokio::spawn(async move {
-                                loop {
-                                    if let Err(e) = handler.handle().await {
-                                        eprintln!("{e}");
-                                        break;
-                                    }
-                                }
-                            });
+                    }
+                };
+                if let Action::Cancel = req.action {
+                    self.cancel(req).await;
+                    return Ok(());
+                }
+                if let Action::Unsubscribe = req.action {
+                    let id = req.id;
+                    if let Err(e) = self.unsubscribe(req).await {
+                        let _ = self.client.error(id, e.to_string());
+                    }
+                    return Ok(());
+                }
+                if req.service == SERVICES {
+                    self.services(req).await;
+                    return Ok(());
+                }
+                if req.service == UNSUBSCRIBE {
+                    self.unsubscribe_request(req).await;
+                    return Ok(());
+                }
+                let Ok(service) = self.connection_map.route(&req.service).await else {
+                    let _ = self.client.error(req.id, "Invalid Service");
+                    return Ok(());
+                };
+                match service.service_type {
+                    ServiceType::Request => {
+                        let Action::Request(payload) = req.action else {
+                            self.subscribe(service, req);
+                            return Ok(());
+                        };
+                        let timeout = req
+                            .timeout
+                            .or(service.timeout)
+                            .unwrap_or_else(|| self.connection_map.request_timeout());
+                        let pending = Pending {
+                            service: req.service.clone(),
+                            instance: service.instance,
+                            serial: self.client.serial.clone(),
+                            client: self.id,
+                            id: req.id,
+                            tag: req.tag,
+                            request: match &payload {
+                                Payload::Text(text) => text.clone(),
+                                Payload::Binary(_) => String::new(),
+                            },
+                            payload: payload.clone(),
+                            streamed: false,
+                            timeout,
+                            deadline: Instant::now() + timeout,
+                        };
+                        let id = self.connection_map.pending.insert(pending.clone()).await?;
+                        if service.request(id, &pending, payload).is_err() {
+                            self.connection_map.pending.remove(id).await;
+                            self.connection_map.fail(&pending, "Service Unavailable").await;
+                            return Ok(());
                         }
+                        self.connection_map.watch(id, pending.deadline);
                     }
-                } else {
-                    let _ = self.send("!Invalid Request".into());
+                    ServiceType::Broadcast => self.subscribe(service, req),
                 }
                 Ok(())
             }
-            Ok(Message::Binary(bytes)) => {
-                unimplemented!()
-            }
             Ok(msg) => {
                 println!("{msg}");
                 Ok(())
@@ -215,6 +430,127 @@ impl ClientHandler {
     }
 }
 
+impl ClientHandler {
+    /// Lists every registered service, or describes the one named in the payload.
+    async fn services(&self, req: ClientRequest) {
+        let name = match req.action {
+            Action::Request(Payload::Text(name)) if !name.is_empty() => Some(name),
+            _ => None,
+        };
+        let services = ServiceEntry::group(self.connection_map.service_map.list().await);
+        let json: Result<String> = match &name {
+            Some(name) => services
+                .iter()
+                .find(|entry| &entry.name == name)
+                .ok_or_else(|| Error::msg("Invalid Service"))
+                .and_then(|entry| Ok(to_string(entry)?)),
+            None => to_string(&services).map_err(Error::from),
+        };
+        self.answer(
+            SERVICES,
+            req.id,
+            req.tag,
+            name.unwrap_or_default(),
+            match json {
+                Ok(json) => Answer::Reply(Payload::Text(json)),
+                Err(e) => Answer::Error(e.to_string()),
+            },
+        );
+    }
+    /// For clients that can only send requests, the payload is what would follow `-`.
+    async fn unsubscribe_request(&self, req: ClientRequest) {
+        let target = match &req.action {
+            Action::Request(Payload::Text(target)) => target.clone(),
+            _ => String::new(),
+        };
+        let (service, tag) = target
+            .split_once("#")
+            .map(|(s, t)| (String::from(s), Some(String::from(t))))
+            .unwrap_or((target.clone(), None));
+        let answer = if service.is_empty() && tag.as_deref().is_none_or(str::is_empty) {
+            Answer::Error(String::from("Service Name Unspecified"))
+        } else {
+            let unsubscribe = ClientRequest {
+                service,
+                tag,
+                id: req.id,
+                timeout: None,
+                topic: None,
+                action: Action::Unsubscribe,
+            };
+            match self.unsubscribe(unsubscribe).await {
+                Ok(()) => Answer::Reply(Payload::Text(String::from("true"))),
+                Err(e) => Answer::Error(e.to_string()),
+            }
+        };
+        self.answer(UNSUBSCRIBE, req.id, req.tag, target, answer);
+    }
+    /// Replies to a request the server answers itself.
+    fn answer(&self, service: &str, id: Option<u64>, tag: Option<String>, request: String, answer: Answer) {
+        let pending = Pending {
+            service: String::from(service),
+            instance: 0,
+            serial: self.client.serial.clone(),
+            client: self.id,
+            id,
+            tag,
+            request,
+            payload: Payload::Text(String::new()),
+            streamed: false,
+            timeout: Duration::ZERO,
+            deadline: Instant::now(),
+        };
+        let _ = self.client.reply(&pending, answer);
+    }
+    fn subscribe(&self, service: Service, req: ClientRequest) {
+        match service.hub {
+            Some(hub) => hub.subscribe(self.client.clone(), self.id, req.service, req.tag, req.topic),
+            None => {
+                let _ = self.client.error(req.id, "Not a Broadcast Service");
+            }
+        }
+    }
+    async fn unsubscribe(&self, req: ClientRequest) -> Result<()> {
+        if req.service.is_empty() {
+            let owner = req.tag.unwrap_or_default();
+            for service in self.connection_map.service_map.list().await {
+                if let Some(hub) = service.hub {
+                    hub.release(&self.client.serial, self.id, &owner);
+                }
+            }
+            return Ok(());
+        }
+        self.connection_map.service_map.get(&req.service).await?;
+        // by the name it was made with, `mpd` may resolve to a newer version by now
+        for service in self.connection_map.service_map.list().await {
+            if let Some(hub) = service.hub {
+                hub.unsubscribe(&self.client.serial, self.id, &req.service, req.tag.as_deref());
+            }
+        }
+        Ok(())
+    }
+    async fn cancel(&self, req: ClientRequest) {
+        let (serial, client) = (self.client.serial.clone(), self.id);
+        let cancelled = self
+            .connection_map
+            .pending
+            .drain(move |p| {
+                p.serial == serial
+                    && p.client == client
+                    && match req.id {
+                        Some(id) => p.id == Some(id),
+                        None => p.service == req.service && p.tag == req.tag,
+                    }
+            })
+            .await;
+        for (id, pending) in cancelled {
+            if let Ok(service) = self.connection_map.service_map.instance(pending.instance).await {
+                let _ = service.cancel(id, &pending);
+            }
+        }
+    }
+}
+
 impl ConnectionIO for ClientHandler {
     async fn read(&mut self) -> Result<Message> {
         self.client.read().await
@@ -1,19 +1,74 @@
+use crate::hub::Lag;
+use crate::service::{Conflict, Dispatch};
 use anyhow::{Error, Result};
 use directories::ProjectDirs;
-use serde::{Deserialize, Serialize};
-use std::env;
-use std::fs::File;
 use os_path::OsPath;
-use std::path::Path;
-use toml::from_str;
+use serde::Deserialize;
+use std::env;
+use std::fs::read_to_string;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tokio::time::interval;
+use toml::{Table, from_str};
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    /// Seconds a client waits on a reply before it gets a timeout error instead.
+    pub request_timeout: u64,
+    /// Messages queued per broadcast subscriber before `broadcast_lag` kicks in.
+    pub broadcast_buffer: usize,
+    /// What to do with a subscriber whose queue is full, unless the service asked for something else.
+    pub broadcast_lag: Lag,
+    /// Seconds a connection may stay silent, pings included, before it counts as dead and is closed.
+    pub liveness_timeout: u64,
+    /// What a service registering under a taken name does to the one already there.
+    pub service_conflict: Conflict,
+    /// Which instance of a pool gets a request, `round_robin` or `least_in_flight`.
+    pub pool_dispatch: Dispatch,
+    #[serde(flatten)]
+    unknown: Table,
+}
 
-#[derive(Deserialize, Debug, Clone)]
+impl ServerConfig {
+    pub fn url(&self) -> String {
+        format!("ws://{}:{}", self.address, self.port)
+    }
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout)
+    }
+    pub fn liveness_timeout(&self) -> Duration {
+        Duration::from_secs(self.liveness_timeout)
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: String::from("localhost"),
+            port: 6969,
+            request_timeout: 30,
+            broadcast_buffer: 64,
+            broadcast_lag: Lag::default(),
+            liveness_timeout: 75,
+            service_conflict: Conflict::default(),
+            pool_dispatch: Dispatch::default(),
+            unknown: Table::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
 pub struct DeviceConfig {
     pub usb_ports: Option<Vec<u8>>,
-    pub local_port: u16,
     pub remote_port: u16,
     pub app_path: OsPath,
     pub cleaner_path: OsPath,
+    #[serde(flatten)]
+    unknown: Table,
 }
 
 #[cfg(target_os = "linux")]
@@ -21,17 +76,96 @@ impl Default for DeviceConfig {
     fn default() -> Self {
         Self {
             usb_ports: None,
-            local_port: 6969,
             remote_port: 6969,
             app_path: OsPath::from("/usr/share/zeitop/base.apk"),
             cleaner_path: OsPath::from("/usr/share/zeitop/cleaner.jar"),
+            unknown: Table::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ServiceToggle {
+    pub enabled: bool,
+    #[serde(flatten)]
+    unknown: Table,
+}
+
+impl Default for ServiceToggle {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            unknown: Table::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MpdConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    #[serde(flatten)]
+    unknown: Table,
+}
+
+impl Default for MpdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            host: String::from("127.0.0.1"),
+            port: 6600,
+            unknown: Table::new(),
         }
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ObsConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub password: Option<String>,
+    #[serde(flatten)]
+    unknown: Table,
+}
+
+impl Default for ObsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            host: String::from("127.0.0.1"),
+            port: 4455,
+            password: Some(String::from("1234567809")),
+            unknown: Table::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ServicesConfig {
+    pub lib: ServiceToggle,
+    pub page: ServiceToggle,
+    pub sysinfo: ServiceToggle,
+    pub mpd: MpdConfig,
+    pub obs: ObsConfig,
+    pub pulse: ServiceToggle,
+    #[serde(flatten)]
+    unknown: Table,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
 pub struct Config {
-    pub device_config: DeviceConfig,
+    pub server: ServerConfig,
+    pub device: DeviceConfig,
+    pub services: ServicesConfig,
+    #[serde(flatten)]
+    unknown: Table,
 }
 
 impl Config {
@@ -42,4 +176,83 @@ impl Config {
             panic!("something is broken. fix it.");
         }
     }
+    pub fn path() -> OsPath {
+        Self::dir().join("config.toml")
+    }
+    /// Missing file means defaults, anything else that goes wrong is an error.
+    pub fn load(path: &OsPath) -> Result<Self> {
+        if !path.exists() {
+            println!("Config => Missing :: {path} (using defaults)");
+            return Ok(Self::default());
+        }
+        let config = read_to_string(path)
+            .map_err(|e| Error::msg(format!("Can not read {path} :: {e}")))?;
+        let config: Self =
+            from_str(&config).map_err(|e| Error::msg(format!("Invalid config {path} :: {e}")))?;
+        for key in config.unknown_keys() {
+            eprintln!("Config => Unknown :: {key} (ignored)");
+        }
+        Ok(config)
+    }
+    /// Keys none of the sections know, a typo or one from a newer version, as `section.key`.
+    fn unknown_keys(&self) -> Vec<String> {
+        let services = &self.services;
+        [
+            ("", &self.unknown),
+            ("server.", &self.server.unknown),
+            ("device.", &self.device.unknown),
+            ("services.", &services.unknown),
+            ("services.lib.", &services.lib.unknown),
+            ("services.page.", &services.page.unknown),
+            ("services.sysinfo.", &services.sysinfo.unknown),
+            ("services.mpd.", &services.mpd.unknown),
+            ("services.obs.", &services.obs.unknown),
+            ("services.pulse.", &services.pulse.unknown),
+        ]
+        .into_iter()
+        .flat_map(|(section, unknown)| unknown.keys().map(move |key| format!("{section}{key}")))
+        .collect()
+    }
+    /// Where services should find the server: `ZEITOP_URL`, then config.toml, then the default port.
+    pub fn discover_url() -> String {
+        if let Ok(url) = env::var("ZEITOP_URL") {
+            return url;
+        }
+        Self::load(&Self::path())
+            .map(|config| config.server.url())
+            .unwrap_or_else(|_| ServerConfig::default().url())
+    }
+    /// Polls `path` and publishes every config that parses and differs from the last one.
+    pub fn watch(path: OsPath, config: Config) -> watch::Receiver<Config> {
+        let (tx, rx) = watch::channel(config);
+        tokio::spawn(async move {
+            let mut modified = Self::modified(&path).await;
+            let mut poll = interval(Duration::from_secs(1));
+            while !tx.is_closed() {
+                poll.tick().await;
+                let now = Self::modified(&path).await;
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+                match Self::load(&path) {
+                    Ok(config) => {
+                        tx.send_if_modified(|old| {
+                            if *old == config {
+                                return false;
+                            }
+                            println!("Config => Reload :: {path}");
+                            *old = config;
+                            true
+                        });
+                    }
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
+        });
+        rx
+    }
+    async fn modified(path: &OsPath) -> Option<SystemTime> {
+        tokio::fs::metadata(path).await.ok()?.modified().ok()
+    }
 }
@@ -0,0 +1,158 @@
+use crate::admin::{ClientEntry, ServiceEntry};
+use crate::cli::{CtlArgs, CtlCommand};
+use crate::config::Config;
+use crate::frame::{self, Payload};
+use anyhow::{Error, Result};
+use futures::{SinkExt, StreamExt};
+use serde_json::from_str;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+use tungstenite::Message;
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+static CTL_SERIAL: &str = "zeitop-ctl";
+static CTL_TAG: &str = "#ctl";
+
+pub async fn run(args: CtlArgs) -> Result<()> {
+    let url = match args.url {
+        Some(url) => url,
+        None => match args.config {
+            Some(path) => Config::load(&path.into())?.server.url(),
+            None => Config::discover_url(),
+        },
+    };
+    match args.command {
+        CtlCommand::Clients => {
+            let clients: Vec<ClientEntry> = from_str(&admin(&url, "clients").await?)?;
+            for client in clients {
+                println!("{}\t{}", client.serial, client.id);
+            }
+        }
+        CtlCommand::Services => {
+            let services: Vec<ServiceEntry> = from_str(&admin(&url, "services").await?)?;
+            for service in services {
+                let Some(schema) = service.schema else {
+                    println!("{}\t{:?}", service.name, service.service_type);
+                    continue;
+                };
+                println!(
+                    "{}\t{:?}\t{}",
+                    service.name,
+                    service.service_type,
+                    schema.description.unwrap_or_default()
+                );
+                for command in schema.commands {
+                    let args: Vec<String> = command
+                        .args
+                        .iter()
+                        .map(|arg| match arg.optional {
+                            true => format!(" [{}:{:?}]", arg.name, arg.arg_type),
+                            false => format!(" <{}:{:?}>", arg.name, arg.arg_type),
+                        })
+                        .collect();
+                    println!(
+                        "  {}{}\t{}",
+                        command.name,
+                        args.concat(),
+                        command.description.unwrap_or_default()
+                    );
+                }
+            }
+        }
+        CtlCommand::Kick { serial, id } => {
+            admin(&url, &format!("kick {serial} {id}")).await?;
+        }
+        CtlCommand::Request {
+            service,
+            payload,
+            timeout: secs,
+        } => {
+            let mut ws = connect(&url, CTL_SERIAL).await?;
+            ws.send(Message::text(format!("&{service}{CTL_TAG}::{}", payload.join(" "))))
+                .await?;
+            // streamed replies come as `>` chunks until a chunk header without data
+            loop {
+                let reply = timeout(Duration::from_secs(secs), next_text(&mut ws))
+                    .await
+                    .map_err(|_| Error::msg("Request timed out"))??;
+                match reply.strip_prefix(">") {
+                    Some(chunk) if chunk.contains("::") => println!("{}", data(chunk)?),
+                    Some(_) => break,
+                    None => {
+                        println!("{}", data(&reply)?);
+                        break;
+                    }
+                }
+            }
+            let _ = ws.close(None).await;
+        }
+        CtlCommand::Tail { service, topic } => {
+            let mut ws = connect(&url, CTL_SERIAL).await?;
+            let pattern = topic.map(|t| format!("::{t}")).unwrap_or_default();
+            // `*` so request services that broadcast take it as a subscription too
+            ws.send(Message::text(format!("*{service}{CTL_TAG}{pattern}"))).await?;
+            loop {
+                let message = next_text(&mut ws).await?;
+                // service#tag@topic::payload
+                match message.split_once("::").and_then(|(header, _)| header.split_once("@")) {
+                    Some((_, topic)) => println!("{topic}\t{}", data(&message)?),
+                    None => println!("{}", data(&message)?),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn admin(url: &str, command: &str) -> Result<String> {
+    let mut ws = connect(url, "$admin").await?;
+    ws.send(Message::text(command)).await?;
+    let reply = next_text(&mut ws).await?;
+    let _ = ws.close(None).await;
+    if let Some(e) = reply.strip_prefix("!") {
+        return Err(Error::msg(String::from(e)));
+    }
+    Ok(reply)
+}
+
+/// Connects and sends the handshake, resolving once the server answered `@Ok`.
+async fn connect(url: &str, handshake: &str) -> Result<WebSocket> {
+    let (mut ws, _) = connect_async(url).await?;
+    ws.send(Message::text(handshake)).await?;
+    match next_text(&mut ws).await?.as_str() {
+        "@Ok" => Ok(ws),
+        e => Err(Error::msg(String::from(e.strip_prefix("!").unwrap_or(e)))),
+    }
+}
+
+async fn next_text(ws: &mut WebSocket) -> Result<String> {
+    loop {
+        match ws.next().await {
+            Some(Ok(Message::Text(text))) if text.as_str() == "?" => {
+                ws.send(Message::text("?")).await?;
+            }
+            Some(Ok(Message::Text(text))) => return Ok(text.as_str().to_owned()),
+            // binary payloads are only summarized, they are rarely meant for a terminal
+            Some(Ok(msg @ Message::Binary(_))) => {
+                if let Some((header, Some(Payload::Binary(bytes)))) = frame::split(&msg) {
+                    return Ok(format!("{header}::<{} bytes>", bytes.len()));
+                }
+            }
+            Some(Ok(Message::Close(_))) | None => return Err(Error::msg("Connection closed")),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+/// Strips the routing header off a message addressed to a client.
+fn data(msg: &str) -> Result<&str> {
+    let data = msg.split_once("::").map(|(_, d)| d).unwrap_or(msg);
+    if let Some(e) = data.strip_prefix("!") {
+        return Err(Error::msg(String::from(e)));
+    }
+    Ok(data)
+}
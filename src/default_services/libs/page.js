@@ -49,6 +49,22 @@ class Page {
     subscribe(service, callback, tag) {
         this.zeitop.subscribe(service, callback, this.identifier + "-" + this.zeitop.auto_num(service, tag));
     }
+    // through the server's built-in `unsubscribe` service, requests are all a client needs
+    unsubscribe(service, tag) {
+        this.zeitop.request("unsubscribe", service + "#" + tag, () => {}, "");
+    }
+    // ends every subscription this page made, they are all tagged with its identifier
+    unload() {
+        this.element.remove();
+        if (this.style) {
+            this.style.remove();
+        }
+        this.unsubscribe("", this.identifier);
+    }
+    replace_with(page) {
+        this.element.replaceWith(page.element);
+        this.unload();
+    }
     append_to(element) {
         element.append(this.element);
     }
@@ -120,19 +136,30 @@ class Page {
             callback(JSON.parse(res));
         }, "query-####");
     }
-    get_base64(path, callback) {
-        this.request("page", this.name + "/" + path, callback, "get_base64-####");
+    // assets come in binary frames, as a Blob or an ArrayBuffer depending on the socket
+    get_blob(path, callback) {
+        this.request("page", this.name + "/" + path, (data) => {
+            callback(data == null ? null : new Blob([data]));
+        }, "get_blob-####");
     }
     get(path, callback) {
-        this.get_base64(path, (base64) => {
-            callback(window.atob(base64));
+        this.get_blob(path, (blob) => {
+            if (blob == null) {
+                callback(null);
+            } else {
+                blob.text().then(callback);
+            }
         });
     }
     load_base64_ttf(font_family, b64) {
         head.append(this.create_style("@font-face {font-family: '" + font_family + "';src: url(data:font/truetype;charset=utf-8;base64," + b64 + ") format('truetype')}"));
     }
     load_ttf(font_family ,path) {
-        this.get_base64(path, (base64) => {this.load_base64_ttf(font_family, base64)});
+        this.get_blob(path, (blob) => {
+            if (blob) {
+                head.append(this.create_style("@font-face {font-family: '" + font_family + "';src: url(" + URL.createObjectURL(blob) + ") format('truetype')}"));
+            }
+        });
     }
 
     getElementById(id) {
@@ -5,8 +5,104 @@ pub mod sysinfo;
 pub mod obs;
 pub mod pulse;
 
+use crate::config::ServicesConfig;
 use anyhow::Result;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use lib::LibService;
+use mpd::MpdService;
+use obs::ObsService;
+use page::PageService;
+use pulse::PulseAudioService;
+use sysinfo::SysInfoService;
 
 pub trait DefaultService {
-    async fn run() -> Result<()>;
+    type Config: Clone + PartialEq + Send + 'static;
+    /// Runs until the service fails or its task gets aborted.
+    fn run(url: String, config: Self::Config) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Owns the task of every running default service so they can be restarted one by one.
+pub struct DefaultServices {
+    url: String,
+    config: ServicesConfig,
+    handles: HashMap<&'static str, JoinHandle<()>>,
+}
+
+impl DefaultServices {
+    pub fn start(url: String, config: &ServicesConfig) -> Self {
+        let mut this = Self {
+            url,
+            config: config.clone(),
+            handles: HashMap::new(),
+        };
+        this.spawn::<LibService>("lib", config.lib.enabled, config.lib.clone());
+        this.spawn::<PageService>("page", config.page.enabled, config.page.clone());
+        this.spawn::<SysInfoService>("sysinfo", config.sysinfo.enabled, config.sysinfo.clone());
+        this.spawn::<MpdService>("mpd", config.mpd.enabled, config.mpd.clone());
+        this.spawn::<ObsService>("obs", config.obs.enabled, config.obs.clone());
+        this.spawn::<PulseAudioService>("pulse", config.pulse.enabled, config.pulse.clone());
+        this
+    }
+    /// Restarts only the services whose section changed, or all of them once the server moved to another `url`.
+    pub async fn apply(&mut self, url: String, config: &ServicesConfig) {
+        let moved = self.url != url;
+        self.url = url;
+        let old = self.config.clone();
+        if moved || old.lib != config.lib {
+            self.restart::<LibService>("lib", config.lib.enabled, config.lib.clone())
+                .await;
+        }
+        if moved || old.page != config.page {
+            self.restart::<PageService>("page", config.page.enabled, config.page.clone())
+                .await;
+        }
+        if moved || old.sysinfo != config.sysinfo {
+            self.restart::<SysInfoService>("sysinfo", config.sysinfo.enabled, config.sysinfo.clone())
+                .await;
+        }
+        if moved || old.mpd != config.mpd {
+            self.restart::<MpdService>("mpd", config.mpd.enabled, config.mpd.clone())
+                .await;
+        }
+        if moved || old.obs != config.obs {
+            self.restart::<ObsService>("obs", config.obs.enabled, config.obs.clone())
+                .await;
+        }
+        if moved || old.pulse != config.pulse {
+            self.restart::<PulseAudioService>("pulse", config.pulse.enabled, config.pulse.clone())
+                .await;
+        }
+        self.config = config.clone();
+    }
+    async fn restart<S: DefaultService>(&mut self, name: &'static str, enabled: bool, config: S::Config) {
+        if let Some(handle) = self.handles.remove(name) {
+            handle.abort();
+            let _ = handle.await;
+            println!("DefaultService => Stop :: {name}");
+        }
+        self.spawn::<S>(name, enabled, config);
+    }
+    fn spawn<S: DefaultService>(&mut self, name: &'static str, enabled: bool, config: S::Config) {
+        if !enabled {
+            return;
+        }
+        println!("DefaultService => Start :: {name}");
+        let url = self.url.clone();
+        let handle = tokio::spawn(async move {
+            // the old instance may still be registered for a moment after a restart
+            for _ in 0..5 {
+                match S::run(url.clone(), config.clone()).await {
+                    Ok(()) => break,
+                    Err(e) => eprintln!("DefaultService => {name} :: {e}"),
+                }
+                sleep(Duration::from_secs(1)).await;
+            }
+        });
+        self.handles.insert(name, handle);
+    }
 }
@@ -1,19 +1,19 @@
 use super::DefaultService;
-use anyhow::Result;
+use anyhow::{Error, Result};
 use mpd_client::{
     Client,
-    client::ConnectionEvent,
-    commands::{CurrentSong, Next, Play, Previous, SetPause, Status},
-    tag::Tag,
+    client::{ConnectionEvent, Subsystem},
+    commands::{CurrentSong, Next, Previous, SetPause, Status},
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::to_string;
-use std::collections::HashMap;
 use std::time::Duration;
+use tokio::select;
 use tokio::net::TcpStream;
-use tokio::task::spawn_blocking;
 
-use crate::service::{BroadcastMessage, BroadcastService, Reply, RequestService};
+use crate::config::MpdConfig;
+use crate::schema::Schema;
+use crate::service::{BroadcastMessage, Publisher, Request, RequestService};
 
 pub struct MpdService {}
 
@@ -43,93 +43,31 @@ struct StatusSer {
 }
 
 impl DefaultService for MpdService {
-    async fn run() -> Result<()> {
-        let mut mpdctl = RequestService::new("mpd").await?;
-        let mpdevents = BroadcastService::new("mpd-events").await?;
-        let stream = TcpStream::connect("127.0.0.1:6600").await.unwrap();
-        let (mpd, mut event) = Client::connect(stream).await.unwrap();
-        tokio::spawn(async move {
-            loop {
-                if let Some(req) = mpdctl.next().await {
-                    match req.request.as_str() {
-                        "play" => {
-                            let _ = req
-                                .reply(Reply::Text(
-                                    mpd.command(SetPause(false)).await.is_ok().to_string(),
-                                ))
-                                .await;
-                        }
-                        "pause" => {
-                            let _ = req
-                                .reply(Reply::Text(
-                                    mpd.command(SetPause(true)).await.is_ok().to_string(),
-                                ))
-                                .await;
-                        }
-                        "next" => {
-                            let _ = req
-                                .reply(Reply::Text(mpd.command(Next).await.is_ok().to_string()))
-                                .await;
-                        }
-                        "prev" => {
-                            let _ = req
-                                .reply(Reply::Text(mpd.command(Previous).await.is_ok().to_string()))
-                                .await;
-                        }
-                        "currentsong" => {
-                            let Ok(Some(currentsong)) = mpd.command(CurrentSong).await else {
-                                let _ = req
-                                    .reply(Reply::Error("Error Current Song Unavailable"))
-                                    .await;
-                                continue;
-                            };
-                            let songinfo = SongInfo {
-                                title: currentsong.song.title().map(|t| t.to_owned()),
-                                artists: currentsong.song.artists().to_vec(),
-                                album: currentsong.song.album().map(|a| a.to_owned()),
-                                album_artists: currentsong.song.album_artists().to_vec(),
-                            };
-                            let _ = req
-                                .reply(Reply::Text(to_string(&songinfo).unwrap_or_default()))
-                                .await;
-                        }
-                        "status" => {
-                            let Ok(status) = mpd.command(Status).await else {
-                                let _ = req.reply(Reply::Error("Error Status Unavailable")).await;
-                                continue;
-                            };
-                            let statusser = StatusSer {
-                                volume: status.volume,
-                                state: match status.state {
-                                    mpd_client::responses::PlayState::Stopped => PlayState::Stopped,
-                                    mpd_client::responses::PlayState::Playing => PlayState::Playing,
-                                    mpd_client::responses::PlayState::Paused => PlayState::Paused,
-                                },
-                                repeat: status.repeat,
-                                random: status.random,
-                                consume: status.consume,
-                                elapsed: status.elapsed,
-                                duration: status.duration,
-                            };
-                            let _ = req
-                                .reply(Reply::Text(to_string(&statusser).unwrap_or_default()))
-                                .await;
-                        }
-                        request => {
-                            let _ = req.reply(Reply::Error("Invalid Request")).await;
-                            println!("Requested => {request} :: Unavailable");
-                        }
-                    }
-                }
-            }
-        });
-        tokio::spawn(async move {
+    type Config = MpdConfig;
+
+    async fn run(url: String, config: MpdConfig) -> Result<()> {
+        let mpdctl = RequestService::builder("mpd")
+            .url(url)
+            .schema(schema())
+            .connect()
+            .await?;
+        let mpdevents = mpdctl.publisher();
+        let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+        let (mpd, mut event) = Client::connect(stream).await?;
+        let state = mpd.clone();
+        let requests = mpdctl.serve_commands(move |command, req| handle(mpd.clone(), command, req));
+        let events = async move {
+            retain_state(&state, &mpdevents).await;
             loop {
                 match event.next().await {
                     Some(ConnectionEvent::SubsystemChange(subsystem)) => {
                         println!("{subsystem:?}");
+                        let name = format!("{subsystem:?}");
+                        if let Subsystem::Player | Subsystem::Mixer | Subsystem::Options = subsystem {
+                            retain_state(&state, &mpdevents).await;
+                        }
                         let _ = mpdevents
-                            .broadcast(BroadcastMessage::Text(format!("{subsystem:?}")))
+                            .publish(&format!("mpd/{}", name.to_lowercase()), BroadcastMessage::Text(name))
                             .await;
                     }
                     Some(ConnectionEvent::ConnectionClosed(e)) => {
@@ -138,7 +76,106 @@ impl DefaultService for MpdService {
                     None => {}
                 }
             }
-        });
+        };
+        // the events loop never ends on its own, the service is done once requests are
+        select! {
+            result = requests => result?,
+            _ = events => {}
+        }
         Ok(())
     }
 }
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MpdCommand {
+    Play,
+    Pause,
+    Next,
+    Prev,
+    #[serde(rename = "currentsong")]
+    CurrentSong,
+    Status,
+}
+
+fn schema() -> Schema {
+    Schema::of::<MpdCommand>(
+        "Playback control of the configured mpd server. Subscribers get the name of each subsystem that \
+         changed under the topic `mpd/<subsystem>`, the current status and song retained as json under \
+         `mpd/status` and `mpd/song`",
+    )
+        .describe("play", "Start playback")
+        .describe("pause", "Pause playback")
+        .describe("next", "Skip to the next song")
+        .describe("prev", "Go back to the previous song")
+        .describe("currentsong", "Title, artists and album of the current song, as json")
+        .describe("status", "Volume, state and playback options, as json")
+}
+
+async fn handle(mpd: Client, command: MpdCommand, req: Request) {
+    match command {
+        MpdCommand::Play => {
+            let _ = req.respond(Ok(mpd.command(SetPause(false)).await.is_ok())).await;
+        }
+        MpdCommand::Pause => {
+            let _ = req.respond(Ok(mpd.command(SetPause(true)).await.is_ok())).await;
+        }
+        MpdCommand::Next => {
+            let _ = req.respond(Ok(mpd.command(Next).await.is_ok())).await;
+        }
+        MpdCommand::Prev => {
+            let _ = req.respond(Ok(mpd.command(Previous).await.is_ok())).await;
+        }
+        MpdCommand::CurrentSong => {
+            let _ = req.respond(song(&mpd).await).await;
+        }
+        MpdCommand::Status => {
+            let _ = req.respond(status(&mpd).await).await;
+        }
+    }
+}
+
+async fn song(mpd: &Client) -> Result<SongInfo> {
+    let Ok(Some(currentsong)) = mpd.command(CurrentSong).await else {
+        return Err(Error::msg("Error Current Song Unavailable"));
+    };
+    Ok(SongInfo {
+        title: currentsong.song.title().map(|t| t.to_owned()),
+        artists: currentsong.song.artists().to_vec(),
+        album: currentsong.song.album().map(|a| a.to_owned()),
+        album_artists: currentsong.song.album_artists().to_vec(),
+    })
+}
+
+async fn status(mpd: &Client) -> Result<StatusSer> {
+    let Ok(status) = mpd.command(Status).await else {
+        return Err(Error::msg("Error Status Unavailable"));
+    };
+    Ok(StatusSer {
+        volume: status.volume,
+        state: match status.state {
+            mpd_client::responses::PlayState::Stopped => PlayState::Stopped,
+            mpd_client::responses::PlayState::Playing => PlayState::Playing,
+            mpd_client::responses::PlayState::Paused => PlayState::Paused,
+        },
+        repeat: status.repeat,
+        random: status.random,
+        consume: status.consume,
+        elapsed: status.elapsed,
+        duration: status.duration,
+    })
+}
+
+/// Retains `mpd/status` and `mpd/song` so pages subscribing later start from the current state.
+async fn retain_state(mpd: &Client, events: &Publisher) {
+    if let Ok(status) = status(mpd).await {
+        let _ = events
+            .retain("mpd/status", BroadcastMessage::Text(to_string(&status).unwrap_or_default()))
+            .await;
+    }
+    // an empty message clears the song once nothing is queued
+    let song = song(mpd).await.map(|s| to_string(&s).unwrap_or_default());
+    let _ = events
+        .retain("mpd/song", BroadcastMessage::Text(song.unwrap_or_default()))
+        .await;
+}
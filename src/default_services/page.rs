@@ -1,12 +1,15 @@
 use super::DefaultService;
-use crate::config::Config;
+use crate::config::{Config, ServiceToggle};
+use crate::schema::Schema;
+use crate::service::layer::{Logging, Validate};
 use crate::service::{Reply, Reply::Text, Request, RequestService};
 use anyhow::{Error, Result};
-use base64::{Engine as _, engine::general_purpose::STANDARD};
+use bytes::Bytes;
 use os_path::OsPath;
-use sass_rs::{Options, OutputStyle, compile_string};
+use sass_rs::{Options, compile_string};
 use serde::Serialize;
 use serde_json::to_string;
+use std::fs::canonicalize;
 use tokio::{
     fs::{File, read_dir, read_to_string},
     io::AsyncReadExt,
@@ -21,10 +24,6 @@ struct Page {
     script: Option<String>,
 }
 
-struct PageServiceConfig {
-    default_page: String,
-}
-
 pub struct PageService {}
 
 #[derive(Serialize)]
@@ -68,63 +67,92 @@ async fn list_dir(path: &OsPath) -> Result<Vec<FileEntry>> {
 }
 
 impl DefaultService for PageService {
-    async fn run() -> Result<()> {
-        let mut request = RequestService::new("page").await?;
-        tokio::spawn(async move {
-            loop {
-                if let Some(req) = request.next().await {
-                    if let Some((page, path)) = req.request.split_once("/") {
-                        let page_dir = Config::dir().join(format!("pages/{page}/"));
-                        if !page_dir.exists() {
-                            let _ = req.reply(Reply::Error("Invalid Page".to_string())).await;
-                            continue;
-                        }
-                        if let Some(query) = path.strip_suffix("?") {
-                            let query_path = page_dir.join(query);
-                            if query_path.exists() && query_path.is_dir() {
-                                let _ = req
-                                    .reply(match list_dir(&query_path).await {
-                                        Ok(entries) => Text(to_string(&entries).unwrap_or_default()),
-                                        Err(e) => Reply::Error(e.to_string()),
-                                    })
-                                .await;
-                            } else {
-                                let _ = req.reply(Text(query_path.exists().to_string())).await;
-                            }
-                            continue;
-                            // TODO:: glob
-                        }
-                        let asset_path = page_dir.join(path);
-                        if !asset_path.exists() {
-                            let _ = req.reply(Reply::Error("Invalid Path".to_string())).await;
-                            continue;
-                        }
-                        let Ok(mut asset_file) = File::open(&asset_path).await else {
-                            let _ = req
-                                .reply(Reply::Error(format!("Cannot Open {asset_path}")))
-                                .await;
-                            continue;
-                        };
-                        let mut asset = Vec::new();
-                        let Ok(_) = asset_file.read_to_end(&mut asset).await else {
-                            let _ = req.reply(Text(format!("!Cannot Read {asset_path}"))).await;
-                            continue;
-                        };
-                        let _ = req.reply(Text(STANDARD.encode(asset))).await;
-                    } else {
-                        match Page::load(&req.request).await {
-                            Ok(page) => {
-                                let _ = req.reply(Text(to_string(&page).unwrap_or_default())).await;
-                            }
-                            Err(e) => {
-                                let _ = req.reply(Text(format!("!{e}"))).await;
-                            }
-                        }
-                    }
-                }
+    type Config = ServiceToggle;
+
+    async fn run(url: String, _config: ServiceToggle) -> Result<()> {
+        let request = RequestService::builder("page")
+            .url(url)
+            .schema(Schema::new(
+                "`name` loads a page, `name/path` one of its assets as binary, `name/dir?` lists a directory",
+            ))
+            .layer(Logging)
+            .layer(Validate(stays_in_page))
+            .connect()
+            .await?;
+        request.serve(handle).await
+    }
+}
+
+/// Requests are joined onto the pages directory, the page and the path in it must stay inside.
+fn stays_in_page(req: &Request) -> Result<()> {
+    let pages = Config::dir().join("pages/");
+    let (page, path) = req.request.split_once("/").unwrap_or((&req.request, ""));
+    inside(&pages, page)?;
+    inside(&pages.join(page), path.strip_suffix("?").unwrap_or(path))
+}
+
+/// Resolves `path` from `dir` with links and `..` followed and checks it ends up in `dir`.
+/// A missing path can't be resolved, the part of it that exists decides.
+fn inside(dir: &OsPath, path: &str) -> Result<()> {
+    let Ok(root) = canonicalize(dir) else {
+        // nothing to reach, the page is invalid anyway
+        return Ok(());
+    };
+    let joined = dir.join(path).to_pathbuf();
+    match joined.ancestors().find_map(|p| canonicalize(p).ok()) {
+        Some(resolved) if resolved.starts_with(&root) => Ok(()),
+        _ => Err(Error::msg("Path Leaves The Page")),
+    }
+}
+
+async fn handle(req: Request) {
+    if let Some((page, path)) = req.request.split_once("/") {
+        let page_dir = Config::dir().join(format!("pages/{page}/"));
+        if !page_dir.exists() {
+            let _ = req.reply(Reply::Error("Invalid Page".to_string())).await;
+            return;
+        }
+        if let Some(query) = path.strip_suffix("?") {
+            let query_path = page_dir.join(query);
+            if query_path.exists() && query_path.is_dir() {
+                let _ = req
+                    .reply(match list_dir(&query_path).await {
+                        Ok(entries) => Text(to_string(&entries).unwrap_or_default()),
+                        Err(e) => Reply::Error(e.to_string()),
+                    })
+                .await;
+            } else {
+                let _ = req.reply(Text(query_path.exists().to_string())).await;
             }
-        });
-        Ok(())
+            return;
+            // TODO:: glob
+        }
+        let asset_path = page_dir.join(path);
+        if !asset_path.exists() {
+            let _ = req.reply(Reply::Error("Invalid Path".to_string())).await;
+            return;
+        }
+        let Ok(mut asset_file) = File::open(&asset_path).await else {
+            let _ = req
+                .reply(Reply::Error(format!("Cannot Open {asset_path}")))
+                .await;
+            return;
+        };
+        let mut asset = Vec::new();
+        let Ok(_) = asset_file.read_to_end(&mut asset).await else {
+            let _ = req.reply(Text(format!("!Cannot Read {asset_path}"))).await;
+            return;
+        };
+        let _ = req.reply(Reply::<String>::Binary(Bytes::from(asset))).await;
+    } else {
+        match Page::load(&req.request).await {
+            Ok(page) => {
+                let _ = req.reply(Text(to_string(&page).unwrap_or_default())).await;
+            }
+            Err(e) => {
+                let _ = req.reply(Text(format!("!{e}"))).await;
+            }
+        }
     }
 }
 
@@ -137,7 +165,7 @@ impl Page {
         let content = read_to_string(page_dir.join("page.html")).await.ok();
         let script = read_to_string(page_dir.join("init.js")).await.ok();
         let mut style = None;
-        if let Some(scss) = read_to_string(page_dir.join("style.scss")).await.ok() {
+        if let Ok(scss) = read_to_string(page_dir.join("style.scss")).await {
             style = Some(
                 spawn_blocking(move || compile_string(&scss, Options::default()))
                     .await?
@@ -1,77 +1,54 @@
 use super::DefaultService;
-use crate::service::{Reply, RequestService};
-use anyhow::Result;
+use crate::config::ServiceToggle;
+use crate::service::ServiceHandler;
+use anyhow::{Error, Result};
 use tokio::process::Command;
 
 pub struct PulseAudioService;
 
 impl DefaultService for PulseAudioService {
-    async fn run() -> Result<()> {
-        let mut request = RequestService::new("pulse").await?;
+    type Config = ServiceToggle;
 
-        tokio::spawn(async move {
-            loop {
-                if let Some(req) = request.next().await {
-                    let parts: Vec<&str> = req.request.split_whitespace().collect();
-                    let cmd = parts.get(0).copied().unwrap_or("");
-
-                    let reply = match cmd {
-                        // ----------------------
-                        // SINK VOLUME CONTROL
-                        // ----------------------
-                        "vol_get_sink" => match get_default_sink_volume().await {
-                            Ok(v) => Reply::Text(v.to_string()),
-                            Err(e) => Reply::Error(e.to_string()),
-                        },
-
-                        "vol_set_sink" => {
-                            if let Some(val) = parts.get(1) {
-                                Reply::Text(set_default_sink_volume(val).await.to_string())
-                            } else {
-                                Reply::Error("usage: vol_set_sink <0-150>".into())
-                            }
-                        }
-
-                        "vol_inc_sink" => {
-                            if let Some(val) = parts.get(1) {
-                                Reply::Text(inc_default_sink_volume(val).await.to_string())
-                            } else {
-                                Reply::Error("usage: vol_inc_sink <percent>".into())
-                            }
-                        }
-
-                        "vol_dec_sink" => {
-                            if let Some(val) = parts.get(1) {
-                                Reply::Text(dec_default_sink_volume(val).await.to_string())
-                            } else {
-                                Reply::Error("usage: vol_dec_sink <percent>".into())
-                            }
-                        }
-
-                        "vol_mute_sink" => Reply::Text(mute_default_sink(true).await.to_string()),
-
-                        "vol_unmute_sink" => {
-                            Reply::Text(mute_default_sink(false).await.to_string())
-                        }
-
-                        // ----------------------
-                        // SOURCE (MIC) CONTROL
-                        // ----------------------
-                        "vol_mute_mic" => Reply::Text(mute_default_source(true).await.to_string()),
-
-                        "vol_unmute_mic" => {
-                            Reply::Text(mute_default_source(false).await.to_string())
-                        }
-
-                        other => Reply::Error(format!("Unknown pulseaudio cmd: {other}")),
-                    };
-
-                    let _ = req.reply(reply).await;
-                }
-            }
-        });
+    async fn run(url: String, _config: ServiceToggle) -> Result<()> {
+        let request = Self::builder().url(url).connect().await?;
+        request.serve_handler(PulseAudioService).await
+    }
+}
 
-        Ok(())
+/// Volume of the default pulseaudio sink and source
+#[zeitop_macros::service(name = "pulse", crate = crate::service)]
+impl PulseAudioService {
+    /// Sink volume in percent
+    async fn vol_get_sink(&self) -> Result<u32> {
+        get_default_sink_volume().await.map_err(Error::msg)
+    }
+    /// Set the sink volume
+    async fn vol_set_sink(&self, volume: u32) -> Result<bool> {
+        Ok(set_default_sink_volume(volume).await)
+    }
+    /// Raise the sink volume
+    async fn vol_inc_sink(&self, percent: u32) -> Result<bool> {
+        Ok(inc_default_sink_volume(percent).await)
+    }
+    /// Lower the sink volume
+    async fn vol_dec_sink(&self, percent: u32) -> Result<bool> {
+        Ok(dec_default_sink_volume(percent).await)
+    }
+    /// Mute the sink
+    async fn vol_mute_sink(&self) -> Result<bool> {
+        Ok(mute_default_sink(true).await)
+    }
+    /// Unmute the sink
+    async fn vol_unmute_sink(&self) -> Result<bool> {
+        Ok(mute_default_sink(false).await)
+    }
+    /// Mute the source
+    async fn vol_mute_mic(&self) -> Result<bool> {
+        Ok(mute_default_source(true).await)
+    }
+    /// Unmute the source
+    async fn vol_unmute_mic(&self) -> Result<bool> {
+        Ok(mute_default_source(false).await)
     }
 }
 
@@ -112,7 +89,7 @@ async fn get_default_sink_volume() -> Result<u32, String> {
     Ok(percent)
 }
 
-async fn set_default_sink_volume(percent: &str) -> bool {
+async fn set_default_sink_volume(percent: u32) -> bool {
     if let Ok(sink) = default_sink().await {
         pactl(&["set-sink-volume", &sink, &format!("{percent}%")])
             .await
@@ -122,7 +99,7 @@ async fn set_default_sink_volume(percent: &str) -> bool {
     }
 }
 
-async fn inc_default_sink_volume(amount: &str) -> bool {
+async fn inc_default_sink_volume(amount: u32) -> bool {
     if let Ok(sink) = default_sink().await {
         pactl(&["set-sink-volume", &sink, &format!("+{amount}%")])
             .await
@@ -132,7 +109,7 @@ async fn inc_default_sink_volume(amount: &str) -> bool {
     }
 }
 
-async fn dec_default_sink_volume(amount: &str) -> bool {
+async fn dec_default_sink_volume(amount: u32) -> bool {
     if let Ok(sink) = default_sink().await {
         pactl(&["set-sink-volume", &sink, &format!("-{amount}%")])
             .await
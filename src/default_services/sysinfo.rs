@@ -1,66 +1,95 @@
 use super::DefaultService;
-use crate::service::{BroadcastMessage, BroadcastService, Reply, RequestService};
-use anyhow::Result;
-use serde_json::to_string;
+use crate::config::ServiceToggle;
+use crate::schema::Schema;
+use crate::service::layer::{Metrics, RateLimit};
+use crate::service::RequestService;
+use anyhow::{Error, Result};
+use serde::Deserialize;
+use serde_json::to_value;
 use std::collections::HashMap;
 use std::process;
-use std::rc::Rc;
-use std::sync::{Arc, RwLock};
-use sysinfo::{Components, Disks, Networks, Pid, System, Users};
-use tokio::task::spawn_blocking;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use sysinfo::{Pid, System, Users};
 
 pub struct SysInfoService {}
 
 impl DefaultService for SysInfoService {
-    async fn run() -> Result<()> {
-        let mut request = RequestService::new("sysinfo").await?;
-        tokio::spawn(async move {
-            let mut sys = System::new_all();
-            let users = Users::new_with_refreshed_list();
-            loop {
-                if let Some(req) = request.next().await {
-                    sys.refresh_cpu_all();
-                    sys.refresh_memory();
-                    match req.request.as_str() {
-                        "user" => {
-                            let this = sys.process(Pid::from_u32(process::id())).unwrap();
-                            let user = users
-                                .get_user_by_id(this.user_id().unwrap())
-                                .unwrap()
-                                .name()
-                                .to_string();
-                            let _ = req.reply(Reply::Text(user.clone())).await;
-                        }
-                        "host" => {
-                            let _ = req
-                                .reply(Reply::Text(System::host_name().unwrap_or_default()))
-                                .await;
-                        }
-                        "cpu" => {
-                            let cpus: HashMap<String, f32> = sys
-                                .cpus()
-                                .iter()
-                                .map(|c| (String::from(c.name()), c.cpu_usage()))
-                                .collect();
-                            let _ = req.reply(Reply::Text(to_string(&cpus).unwrap())).await;
-                        }
-                        "total_mem" => {
-                            let _ = req.reply(Reply::Text(sys.total_memory().to_string())).await;
-                        }
-                        "used_mem" => {
-                            let _ = req.reply(Reply::Text(sys.used_memory().to_string())).await;
-                        }
-                        "uptime" => {
-                            let _ = req.reply(Reply::Text(System::uptime().to_string())).await;
-                        }
-                        request => {
-                            let _ = req.reply(Reply::Error("Invalid Request".to_string())).await;
-                            println!("Requested => {request} :: Unavailable");
+    type Config = ServiceToggle;
+
+    async fn run(url: String, _config: ServiceToggle) -> Result<()> {
+        let metrics = Metrics::of::<SysInfoCommand>();
+        // pages tend to poll, one stuck in a loop shouldn't keep the host busy
+        let request = RequestService::builder("sysinfo")
+            .url(url)
+            .schema(schema())
+            .layer(RateLimit::new(50, Duration::from_secs(1)))
+            .layer(metrics.clone())
+            .connect()
+            .await?;
+        let sys = Arc::new(Mutex::new(System::new_all()));
+        let users = Arc::new(Users::new_with_refreshed_list());
+        request
+            .serve_commands(move |command: SysInfoCommand, req| {
+                let sys = sys.clone();
+                let users = users.clone();
+                let metrics = metrics.clone();
+                async move {
+                    let reply = {
+                        let mut sys = sys.lock().unwrap();
+                        sys.refresh_cpu_all();
+                        sys.refresh_memory();
+                        match command {
+                            SysInfoCommand::User => {
+                                let this = sys.process(Pid::from_u32(process::id())).unwrap();
+                                let user = users
+                                    .get_user_by_id(this.user_id().unwrap())
+                                    .unwrap()
+                                    .name()
+                                    .to_string();
+                                to_value(user)
+                            }
+                            SysInfoCommand::Host => to_value(System::host_name().unwrap_or_default()),
+                            SysInfoCommand::Cpu => {
+                                let cpus: HashMap<String, f32> = sys
+                                    .cpus()
+                                    .iter()
+                                    .map(|c| (String::from(c.name()), c.cpu_usage()))
+                                    .collect();
+                                to_value(cpus)
+                            }
+                            SysInfoCommand::TotalMem => to_value(sys.total_memory()),
+                            SysInfoCommand::UsedMem => to_value(sys.used_memory()),
+                            SysInfoCommand::Uptime => to_value(System::uptime()),
+                            SysInfoCommand::Metrics => to_value(metrics.snapshot()),
                         }
-                    }
+                    };
+                    let _ = req.respond(reply.map_err(Error::from)).await;
                 }
-            }
-        });
-        Ok(())
+            })
+            .await
     }
 }
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SysInfoCommand {
+    User,
+    Host,
+    Cpu,
+    TotalMem,
+    UsedMem,
+    Uptime,
+    Metrics,
+}
+
+fn schema() -> Schema {
+    Schema::of::<SysInfoCommand>("System information of the host")
+        .describe("user", "User running zeitop")
+        .describe("host", "Host name")
+        .describe("cpu", "Usage of each cpu in percent, as json")
+        .describe("total_mem", "Total memory in bytes")
+        .describe("used_mem", "Used memory in bytes")
+        .describe("uptime", "Uptime in seconds")
+        .describe("metrics", "Count, total and longest time of each command, as json")
+}
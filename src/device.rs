@@ -1,8 +1,10 @@
 use crate::config::DeviceConfig;
 use anyhow::{Error, Result};
-use futures::stream::{Stream, StreamExt};
+use futures::stream::StreamExt;
 use nusb::{hotplug::HotplugEvent, list_devices, watch_devices, DeviceId, DeviceInfo};
 use os_path::OsPath;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use tokio::process::Command;
 use tokio::{join, spawn};
 
@@ -10,8 +12,6 @@ pub type Serial = String;
 
 static PACK_NAME: &str = "com.z3phyrl.zeitop";
 static MAIN_CLASS: &str = "com.z3phyrl.MainKt";
-static LOCAL_PORT: u16 = 6969;
-static REMOTE_PORT: u16 = 6969;
 
 async fn adb<'a, I>(serial: &'a str, args: I) -> Result<std::process::Output, std::io::Error>
 where
@@ -37,12 +37,16 @@ async fn reverse(serial: &str, local: u16, remote: u16) -> Result<()> {
     Ok(())
 }
 
+async fn remove_reverse(serial: &str, remote: u16) -> Result<()> {
+    adb(serial, ["reverse", "--remove", &format!("tcp:{remote}")]).await?;
+    Ok(())
+}
+
 async fn is_installed(serial: &str) -> Result<bool> {
-    if adb(serial, ["shell", "pm", "list", "packages", PACK_NAME])
+    if !adb(serial, ["shell", "pm", "list", "packages", PACK_NAME])
         .await?
         .stdout
-        .len()
-        > 0
+        .is_empty()
     {
         Ok(true)
     } else {
@@ -86,49 +90,90 @@ async fn start_app(serial: &str) -> Result<()> {
     Ok(())
 }
 
-pub struct DeviceHandler {}
+struct DeviceState {
+    config: DeviceConfig,
+    local_port: u16,
+    devices: HashMap<DeviceId, Serial>,
+}
+
+#[derive(Clone)]
+pub struct DeviceHandler {
+    state: Arc<Mutex<DeviceState>>,
+}
+
 impl DeviceHandler {
-    pub async fn new(config: DeviceConfig) -> Result<Self> {
+    pub async fn new(config: DeviceConfig, local_port: u16) -> Result<Self> {
+        let this = Self {
+            state: Arc::new(Mutex::new(DeviceState {
+                config,
+                local_port,
+                devices: HashMap::new(),
+            })),
+        };
         let list = list_devices()?;
         for info in list {
-            DeviceHandler::handle_device(config.clone(), info)
-                .await
-                .unwrap();
+            this.handle_device(info).await.unwrap();
         }
         let mut watch = watch_devices()?;
+        let handler = this.clone();
         spawn(async move {
             loop {
                 match watch.next().await {
                     Some(HotplugEvent::Connected(info)) => {
-                        DeviceHandler::handle_device(config.clone(), info)
-                            .await
-                            .unwrap();
+                        handler.handle_device(info).await.unwrap();
                     }
                     Some(HotplugEvent::Disconnected(id)) => {
+                        handler.state.lock().unwrap().devices.remove(&id);
                         println!("< {id:?}");
                     }
-                    None => {
-                        println!("heh");
-                    }
+                    None => break,
                 }
             }
         });
-        Ok(Self {})
+        Ok(this)
     }
-    async fn handle_device(config: DeviceConfig, info: DeviceInfo) -> Result<()> {
-        if !(info
+    /// Picks up a new config, re-running `reverse` on every known device if the ports moved.
+    pub async fn update(&self, config: DeviceConfig, local_port: u16) -> Result<()> {
+        let (old_remote, old_local, serials) = {
+            let mut state = self.state.lock().unwrap();
+            let old = (state.config.remote_port, state.local_port);
+            state.config = config.clone();
+            state.local_port = local_port;
+            (old.0, old.1, state.devices.values().cloned().collect::<Vec<_>>())
+        };
+        if old_remote == config.remote_port && old_local == local_port {
+            return Ok(());
+        }
+        // one device failing shouldn't leave the others on the old port
+        for serial in serials {
+            if old_remote != config.remote_port
+                && let Err(e) = remove_reverse(&serial, old_remote).await
+            {
+                eprintln!("Device => Unreverse :: {serial} :: {e}");
+            }
+            match reverse(&serial, local_port, config.remote_port).await {
+                Ok(()) => println!("Device => Reverse :: {serial} tcp:{} -> tcp:{local_port}", config.remote_port),
+                Err(e) => eprintln!("Device => Reverse :: {serial} :: {e}"),
+            }
+        }
+        Ok(())
+    }
+    async fn handle_device(&self, info: DeviceInfo) -> Result<()> {
+        if !info
             .interfaces()
-            .filter(|i| i.interface_string().is_some_and(|i| i == "ADB Interface"))
-            .count()
-            > 0)
+            .any(|i| i.interface_string().is_some_and(|i| i == "ADB Interface"))
         {
             return Ok(());
         }
         let Some(serial) = info.serial_number() else {
             return Err(Error::msg("No Serial Number"));
         };
+        let (config, local_port) = {
+            let state = self.state.lock().unwrap();
+            (state.config.clone(), state.local_port)
+        };
         wait_for(serial).await?;
-        let _ = join!(reverse(serial, LOCAL_PORT, REMOTE_PORT), async {
+        let _ = join!(reverse(serial, local_port, config.remote_port), async {
             if !is_installed(serial).await.is_ok_and(|i| i) {
                 let _ = join!(
                     install(serial, config.app_path.clone()),
@@ -140,6 +185,11 @@ impl DeviceHandler {
                 });
             }
         });
+        self.state
+            .lock()
+            .unwrap()
+            .devices
+            .insert(info.id(), String::from(serial));
         start_app(serial).await?;
         Ok(())
     }
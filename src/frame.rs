@@ -0,0 +1,102 @@
+use bytes::Bytes;
+use tungstenite::Message;
+
+/// Everything after the routing header of a message.
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Text(String),
+    Binary(Bytes),
+}
+
+/// Splits `header::payload` off a text or binary frame.
+/// Binary frames carry the same utf-8 header as text ones, followed by the raw bytes.
+/// Only text frames may leave the payload out.
+pub fn split(msg: &Message) -> Option<(String, Option<Payload>)> {
+    match msg {
+        Message::Text(text) => Some(match text.as_str().split_once("::") {
+            Some((header, data)) => (String::from(header), Some(Payload::Text(String::from(data)))),
+            None => (String::from(text.as_str()), None),
+        }),
+        Message::Binary(bytes) => {
+            let at = bytes.windows(2).position(|w| w == b"::")?;
+            let header = std::str::from_utf8(&bytes[..at]).ok()?;
+            Some((String::from(header), Some(Payload::Binary(bytes.slice(at + 2..)))))
+        }
+        _ => None,
+    }
+}
+
+/// Builds `header::payload`, as a binary frame when the payload is binary.
+pub fn join(header: &str, payload: Payload) -> Message {
+    match payload {
+        Payload::Text(data) => Message::text(format!("{header}::{data}")),
+        Payload::Binary(data) => {
+            let mut frame = Vec::with_capacity(header.len() + 2 + data.len());
+            frame.extend_from_slice(header.as_bytes());
+            frame.extend_from_slice(b"::");
+            frame.extend_from_slice(&data);
+            Message::binary(frame)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(payload: Option<Payload>) -> Option<String> {
+        match payload {
+            Some(Payload::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn splits_text_at_the_first_separator() {
+        let (header, payload) = split(&Message::text("&mpd#ui::a::b")).unwrap();
+        assert_eq!(header, "&mpd#ui");
+        assert_eq!(text(payload).as_deref(), Some("a::b"));
+    }
+
+    #[test]
+    fn text_without_separator_has_no_payload() {
+        let (header, payload) = split(&Message::text("&mpd#ui")).unwrap();
+        assert_eq!(header, "&mpd#ui");
+        assert!(payload.is_none());
+    }
+
+    #[test]
+    fn empty_text_payload_is_kept() {
+        let (header, payload) = split(&Message::text("&mpd::")).unwrap();
+        assert_eq!(header, "&mpd");
+        assert_eq!(text(payload).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn binary_round_trips_with_separator_in_payload() {
+        let msg = join("serial@1&req", Payload::Binary(Bytes::from_static(b"\x00::\xff")));
+        let (header, payload) = split(&msg).unwrap();
+        assert_eq!(header, "serial@1&req");
+        match payload {
+            Some(Payload::Binary(bytes)) => assert_eq!(&bytes[..], b"\x00::\xff"),
+            _ => panic!("expected a binary payload"),
+        }
+    }
+
+    #[test]
+    fn empty_binary_payload() {
+        let (_, payload) = split(&join("mpd", Payload::Binary(Bytes::new()))).unwrap();
+        assert!(matches!(payload, Some(Payload::Binary(bytes)) if bytes.is_empty()));
+    }
+
+    #[test]
+    fn binary_needs_a_header() {
+        assert!(split(&Message::binary(b"no separator".to_vec())).is_none());
+        assert!(split(&Message::binary(b"\xff\xfe::data".to_vec())).is_none());
+    }
+
+    #[test]
+    fn other_frames_are_not_split() {
+        assert!(split(&Message::Ping(Bytes::new())).is_none());
+    }
+}
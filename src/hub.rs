@@ -0,0 +1,295 @@
+use crate::client::Client;
+use crate::device::Serial;
+use crate::frame::Payload;
+use crate::server::ConnectionIO;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tungstenite::Message;
+
+/// What a broadcast does to a subscriber whose queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Lag {
+    /// Forget the oldest queued message to make room.
+    #[default]
+    DropOldest,
+    /// Close the client's connection, it can't keep up.
+    Disconnect,
+    /// Forget everything queued, the newest message stands for all of them.
+    Coalesce,
+}
+
+/// Fans one broadcast service out to its subscribers, each with its own queue of `capacity` messages.
+#[derive(Debug)]
+pub struct Hub {
+    service: String,
+    lag: Lag,
+    capacity: usize,
+    subscribers: Mutex<Vec<Arc<Subscriber>>>,
+    /// Last retained message of each topic, replayed to new subscribers.
+    retained: Mutex<BTreeMap<String, Payload>>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    client: Client,
+    id: u32,
+    /// The service as the client named it, broadcasts reach it under that name.
+    service: String,
+    tag: Option<String>,
+    /// Only topics matching it get through, everything does without one.
+    pattern: Option<String>,
+    queue: Mutex<VecDeque<(Option<String>, Payload)>>,
+    ready: Notify,
+    gone: Notify,
+    closed: AtomicBool,
+}
+
+impl Subscriber {
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.gone.notify_waiters();
+        self.ready.notify_one();
+    }
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+    fn wants(&self, topic: Option<&str>) -> bool {
+        match (&self.pattern, topic) {
+            (None, _) => true,
+            (Some(pattern), Some(topic)) => matches(pattern, topic),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// `/` separated topics, `*` stands for one level and `**` for any number of them, none included.
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let topic: Vec<&str> = topic.split('/').collect();
+    matches_levels(&pattern, &topic)
+}
+
+fn matches_levels(pattern: &[&str], topic: &[&str]) -> bool {
+    match (pattern.split_first(), topic.split_first()) {
+        (None, None) => true,
+        // whatever follows has to match the rest of the topic from some level on
+        (Some((&"**", pattern)), _) => (0..=topic.len()).any(|at| matches_levels(pattern, &topic[at..])),
+        (Some((&"*", pattern)), Some((_, topic))) => matches_levels(pattern, topic),
+        (Some((level, pattern)), Some((name, topic))) if level == name => matches_levels(pattern, topic),
+        _ => false,
+    }
+}
+
+impl Hub {
+    pub fn new(service: impl Into<String>, lag: Lag, capacity: usize) -> Self {
+        Self {
+            service: service.into(),
+            lag,
+            capacity: capacity.max(1),
+            subscribers: Mutex::new(Vec::new()),
+            retained: Mutex::new(BTreeMap::new()),
+        }
+    }
+    /// Starts forwarding topics matching `pattern` to `client`, retained ones first.
+    /// Subscribing again under the same name and tag only changes the pattern.
+    pub fn subscribe(
+        self: &Arc<Self>,
+        client: Client,
+        id: u32,
+        service: String,
+        tag: Option<String>,
+        pattern: Option<String>,
+    ) {
+        let subscriber = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            if let Some(at) = subscribers
+                .iter()
+                .position(|s| s.client.serial == client.serial && s.id == id && s.service == service && s.tag == tag)
+            {
+                if subscribers[at].pattern == pattern {
+                    return;
+                }
+                subscribers.remove(at).close();
+            }
+            let subscriber = Arc::new(Subscriber {
+                client,
+                id,
+                service,
+                tag,
+                pattern,
+                queue: Mutex::new(VecDeque::new()),
+                ready: Notify::new(),
+                gone: Notify::new(),
+                closed: AtomicBool::new(false),
+            });
+            // under the subscribers lock so no live message can get ahead of them
+            let mut queue = subscriber.queue.lock().unwrap();
+            for (topic, payload) in self.retained.lock().unwrap().iter() {
+                if subscriber.wants(Some(topic)) {
+                    queue.push_back((Some(topic.clone()), payload.clone()));
+                }
+            }
+            drop(queue);
+            subscribers.push(subscriber.clone());
+            subscriber
+        };
+        println!(
+            "Subscribe => {} :: {}@{}",
+            self.service, subscriber.client.serial, subscriber.id
+        );
+        let hub = self.clone();
+        tokio::spawn(async move { hub.forward(subscriber).await });
+    }
+    /// Ends the subscription of one client connection to `service` with exactly this tag.
+    pub fn unsubscribe(&self, serial: &Serial, id: u32, service: &str, tag: Option<&str>) {
+        self.remove(|s| {
+            &s.client.serial == serial && s.id == id && s.service == service && s.tag.as_deref() == tag
+        });
+    }
+    /// Ends the subscriptions of one client connection tagged `owner` or `owner-..`.
+    pub fn release(&self, serial: &Serial, id: u32, owner: &str) {
+        self.remove(|s| {
+            &s.client.serial == serial
+                && s.id == id
+                && s.tag.as_deref().is_some_and(|tag| {
+                    tag.strip_prefix(owner)
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+                })
+        });
+    }
+    /// Ends every subscription of one client connection.
+    pub fn leave(&self, serial: &Serial, id: u32) {
+        self.remove(|s| &s.client.serial == serial && s.id == id);
+    }
+    fn remove(&self, matches: impl Fn(&Subscriber) -> bool) {
+        self.subscribers.lock().unwrap().retain(|s| {
+            if !matches(s) {
+                return true;
+            }
+            println!("Unsubscribe => {} :: {}@{}", self.service, s.client.serial, s.id);
+            s.close();
+            false
+        });
+    }
+    /// Queues `payload` for every subscriber that wants `topic`, a full queue is handled by the hub's [`Lag`].
+    /// A retained message is kept for later subscribers until the next one of its topic, an empty one clears it.
+    pub fn publish(&self, topic: Option<&str>, payload: Payload, retain: bool) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if retain && let Some(topic) = topic {
+            let mut retained = self.retained.lock().unwrap();
+            match &payload {
+                Payload::Text(text) if text.is_empty() => retained.remove(topic),
+                payload => retained.insert(String::from(topic), payload.clone()),
+            };
+        }
+        subscribers.retain(|s| {
+            if !s.wants(topic) {
+                return true;
+            }
+            let mut queue = s.queue.lock().unwrap();
+            if queue.len() >= self.capacity {
+                match self.lag {
+                    Lag::DropOldest => {
+                        queue.pop_front();
+                    }
+                    Lag::Coalesce => queue.clear(),
+                    Lag::Disconnect => {
+                        println!("Lagged => {} :: {}@{}", self.service, s.client.serial, s.id);
+                        let _ = s.client.send(Message::Close(None));
+                        s.close();
+                        return false;
+                    }
+                }
+            }
+            queue.push_back((topic.map(String::from), payload.clone()));
+            s.ready.notify_one();
+            true
+        });
+    }
+    /// Ends every subscription, the service is gone.
+    pub fn close(&self) {
+        for subscriber in self.subscribers.lock().unwrap().drain(..) {
+            subscriber.close();
+        }
+    }
+    /// Sends one message at a time and waits for the client's socket to take it before the next.
+    async fn forward(&self, subscriber: Arc<Subscriber>) {
+        loop {
+            let (topic, payload) = loop {
+                if subscriber.is_closed() {
+                    return;
+                }
+                if let Some(message) = subscriber.queue.lock().unwrap().pop_front() {
+                    break message;
+                }
+                subscriber.ready.notified().await;
+            };
+            let gone = subscriber.gone.notified();
+            if subscriber
+                .client
+                .broadcast(&subscriber.service, subscriber.tag.as_deref(), topic.as_deref(), payload)
+                .is_err()
+            {
+                self.leave(&subscriber.client.serial, subscriber.id);
+                return;
+            }
+            tokio::select! {
+                _ = subscriber.client.flushed() => {}
+                _ = gone => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn exact_topics() {
+        assert!(matches("mpd/player", "mpd/player"));
+        assert!(!matches("mpd/player", "mpd/mixer"));
+        assert!(!matches("mpd/player", "mpd"));
+        assert!(!matches("mpd", "mpd/player"));
+    }
+
+    #[test]
+    fn star_is_one_level() {
+        assert!(matches("mpd/*", "mpd/player"));
+        assert!(matches("*/player", "mpd/player"));
+        assert!(!matches("mpd/*", "mpd"));
+        assert!(!matches("mpd/*", "mpd/player/state"));
+    }
+
+    #[test]
+    fn trailing_double_star_is_any_number_of_levels() {
+        assert!(matches("mpd/**", "mpd"));
+        assert!(matches("mpd/**", "mpd/player"));
+        assert!(matches("mpd/**", "mpd/player/state"));
+        assert!(!matches("mpd/**", "obs/scene"));
+        assert!(matches("**", "anything/at/all"));
+    }
+
+    #[test]
+    fn double_star_in_the_middle() {
+        assert!(matches("mpd/**/volume", "mpd/volume"));
+        assert!(matches("mpd/**/volume", "mpd/mixer/volume"));
+        assert!(matches("mpd/**/volume", "mpd/a/b/volume"));
+        assert!(!matches("mpd/**/volume", "mpd/mixer/volume/left"));
+        assert!(!matches("mpd/**/volume", "mpd/mixer"));
+        assert!(matches("**/volume", "mpd/mixer/volume"));
+        assert!(matches("mpd/**/*", "mpd/player"));
+        assert!(!matches("mpd/**/*", "mpd"));
+    }
+
+    #[test]
+    fn empty_levels_are_levels() {
+        assert!(matches("", ""));
+        assert!(!matches("", "mpd"));
+        assert!(matches("mpd/*", "mpd/"));
+    }
+}
@@ -1,8 +1,34 @@
+mod admin;
+mod cli;
 mod client;
 mod config;
+mod ctl;
 mod device;
+mod frame;
+mod hub;
+mod pending;
+mod protocol;
+mod schema;
 mod server;
 mod service;
 mod default_services;
 
-pub use service::{RequestService, BroadcastService, Request, Reply, BroadcastMessage};
+pub use hub::Lag;
+pub use protocol::Protocol;
+pub use schema::{Schema, Command, Arg, ArgType};
+pub use service::{RequestService, BroadcastService, ServiceBuilder, Request, Reply, ReplyStream, BroadcastMessage, Publisher, Backoff, ConnectionState, Cancellation, ServiceHandler};
+pub use service::layer::{Layer, Next, Logging, RateLimit, Validate, Metrics, Timing};
+pub use zeitop_macros::service;
+#[doc(hidden)]
+pub use service::__private;
+
+/// What the `zeitop` binary runs, not part of the SDK.
+#[doc(hidden)]
+pub mod daemon {
+    pub use crate::cli::{Cli, Command, ServeArgs};
+    pub use crate::config::Config;
+    pub use crate::ctl::run as ctl;
+    pub use crate::default_services::DefaultServices;
+    pub use crate::device::DeviceHandler;
+    pub use crate::server::Server;
+}
@@ -1,49 +1,64 @@
-use anyhow::{Error, Result};
-use tokio::task::spawn_blocking;
-mod client;
-mod config;
-mod default_services;
-mod device;
-mod server;
-mod service;
-
-use config::{Config, DeviceConfig};
-use default_services::{
-    mpd::MpdService, page::PageService, sysinfo::SysInfoService, lib::LibService,
-    DefaultService,
-};
-use device::DeviceHandler;
-use server::Server;
-use tokio::join;
-
-use crate::default_services::{obs::ObsService, pulse::PulseAudioService};
+use anyhow::Result;
+use clap::Parser;
+use zeitop::daemon::{Cli, Command, Config, DefaultServices, DeviceHandler, ServeArgs, Server, ctl};
+use tokio::select;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    match Cli::parse().command {
+        Some(Command::Serve(args)) => serve(args).await,
+        Some(Command::Ctl(args)) => ctl(args).await,
+        None => serve(ServeArgs::default()).await,
+    }
+}
+
+async fn serve(args: ServeArgs) -> Result<()> {
     println!("Hello, world!");
-    let config = Config {
-        device_config: DeviceConfig::default(),
+    let config_path = args.config_path();
+    let mut config = Config::load(&config_path)?;
+    args.apply(&mut config);
+    let mut server = Server::new(&config.server.address, config.server.port).await?;
+    server.set_request_timeout(config.server.request_timeout());
+    server.set_broadcast(config.server.broadcast_lag, config.server.broadcast_buffer);
+    server.set_liveness_timeout(config.server.liveness_timeout());
+    server.set_service_conflict(config.server.service_conflict);
+    server.set_pool_dispatch(config.server.pool_dispatch);
+    let mut default_services = DefaultServices::start(config.server.url(), &config.services);
+    let devices = if args.no_device_handler {
+        None
+    } else {
+        Some(DeviceHandler::new(config.device.clone(), config.server.port).await?)
     };
-    let server = Server::new(config.device_config.local_port).await?;
-    let server_handler = tokio::spawn(async move {
-        loop {
-            let _ = server.handle().await;
+    let mut reload = Config::watch(config_path, config.clone());
+    loop {
+        select! {
+            _ = server.handle() => {}
+            Ok(()) = reload.changed() => {
+                let mut new = reload.borrow_and_update().clone();
+                args.apply(&mut new);
+                if new.server.url() != config.server.url()
+                    && let Err(e) = server.rebind(&new.server.address, new.server.port).await
+                {
+                    // still listening on the old address, phones and services stay there too
+                    eprintln!("{e}");
+                    new.server.address = config.server.address.clone();
+                    new.server.port = config.server.port;
+                }
+                server.set_request_timeout(new.server.request_timeout());
+                server.set_broadcast(new.server.broadcast_lag, new.server.broadcast_buffer);
+                server.set_liveness_timeout(new.server.liveness_timeout());
+                server.set_service_conflict(new.server.service_conflict);
+                server.set_pool_dispatch(new.server.pool_dispatch);
+                if let Some(devices) = &devices
+                    && let Err(e) = devices.update(new.device.clone(), new.server.port).await
+                {
+                    eprintln!("{e}");
+                }
+                default_services
+                    .apply(new.server.url(), &new.services)
+                    .await;
+                config = new;
+            }
         }
-    });
-    run_default_service().await?;
-    DeviceHandler::new(config.device_config.clone()).await?;
-    server_handler.await?;
-    Ok(())
-}
-
-async fn run_default_service() -> Result<()> {
-    let _ = join!(
-        LibService::run(),
-        PageService::run(),
-        SysInfoService::run(),
-        MpdService::run(),
-        ObsService::run(),
-        PulseAudioService::run()
-    );
-    Ok(())
+    }
 }
@@ -0,0 +1,163 @@
+use crate::device::Serial;
+use crate::frame::Payload;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::spawn_blocking;
+use tokio::time::Instant;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Requests forwarded to a service and still waiting on a reply, keyed by a server assigned id.
+pub type PendingMap = Arc<RwLock<BTreeMap<u64, Pending>>>;
+
+/// Where a reply has to go back to.
+#[derive(Debug, Clone)]
+pub struct Pending {
+    /// As the client named it, replies carry that name.
+    pub service: String,
+    /// Which of the connections registered as `service` has the request.
+    pub instance: u64,
+    pub serial: Serial,
+    pub client: u32,
+    /// Id the client picked, only json clients have one.
+    pub id: Option<u64>,
+    pub tag: Option<String>,
+    /// Legacy replies echo the request back.
+    pub request: String,
+    /// Kept so another instance of a pool can take the request over.
+    pub payload: Payload,
+    /// Set by the first chunk of a streamed reply, such a request can't start over elsewhere.
+    pub streamed: bool,
+    pub timeout: Duration,
+    /// Pushed back by `timeout` with every chunk of a streamed reply.
+    pub deadline: Instant,
+}
+
+pub enum Expiry {
+    Expired(Pending),
+    Later(Instant),
+    Gone,
+}
+
+pub trait PendingMapExt {
+    async fn insert(&self, pending: Pending) -> Result<u64>;
+    async fn restore(&self, id: u64, pending: Pending);
+    async fn remove(&self, id: u64) -> Option<Pending>;
+    async fn take_oldest(&self, matches: impl Fn(&Pending) -> bool + Send + 'static) -> Option<Pending>;
+    async fn drain(&self, matches: impl Fn(&Pending) -> bool + Send + 'static) -> Vec<(u64, Pending)>;
+    async fn touch(&self, id: u64) -> Option<Pending>;
+    async fn touch_oldest(&self, matches: impl Fn(&Pending) -> bool + Send + 'static) -> Option<Pending>;
+    async fn expire(&self, id: u64) -> Expiry;
+    async fn in_flight(&self) -> HashMap<u64, usize>;
+}
+
+impl PendingMapExt for PendingMap {
+    async fn insert(&self, pending: Pending) -> Result<u64> {
+        let this = self.clone();
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        spawn_blocking(move || {
+            this.write().unwrap().insert(id, pending);
+        })
+        .await?;
+        Ok(id)
+    }
+    /// Puts a request taken out with `drain` back under its old id.
+    async fn restore(&self, id: u64, pending: Pending) {
+        let this = self.clone();
+        let _ = spawn_blocking(move || {
+            this.write().unwrap().insert(id, pending);
+        })
+        .await;
+    }
+    async fn remove(&self, id: u64) -> Option<Pending> {
+        let this = self.clone();
+        spawn_blocking(move || this.write().unwrap().remove(&id))
+            .await
+            .ok()
+            .flatten()
+    }
+    /// Legacy services reply without an id, so the oldest request that fits the reply is the one.
+    async fn take_oldest(&self, matches: impl Fn(&Pending) -> bool + Send + 'static) -> Option<Pending> {
+        let this = self.clone();
+        spawn_blocking(move || {
+            let mut this = this.write().unwrap();
+            let id = *this.iter().find(|(_, pending)| matches(pending))?.0;
+            this.remove(&id)
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+    async fn drain(&self, matches: impl Fn(&Pending) -> bool + Send + 'static) -> Vec<(u64, Pending)> {
+        let this = self.clone();
+        spawn_blocking(move || {
+            let mut this = this.write().unwrap();
+            let ids: Vec<u64> = this
+                .iter()
+                .filter(|(_, pending)| matches(pending))
+                .map(|(id, _)| *id)
+                .collect();
+            ids.into_iter()
+                .filter_map(|id| this.remove(&id).map(|pending| (id, pending)))
+                .collect()
+        })
+        .await
+        .unwrap_or_default()
+    }
+    async fn touch(&self, id: u64) -> Option<Pending> {
+        let this = self.clone();
+        spawn_blocking(move || {
+            let mut this = this.write().unwrap();
+            let pending = this.get_mut(&id)?;
+            pending.deadline = Instant::now() + pending.timeout;
+            pending.streamed = true;
+            Some(pending.clone())
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+    async fn touch_oldest(&self, matches: impl Fn(&Pending) -> bool + Send + 'static) -> Option<Pending> {
+        let this = self.clone();
+        spawn_blocking(move || {
+            let mut this = this.write().unwrap();
+            let pending = this.values_mut().find(|pending| matches(pending))?;
+            pending.deadline = Instant::now() + pending.timeout;
+            pending.streamed = true;
+            Some(pending.clone())
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+    /// Removes `id` once its deadline passed, otherwise tells when to look again.
+    async fn expire(&self, id: u64) -> Expiry {
+        let this = self.clone();
+        spawn_blocking(move || {
+            let mut this = this.write().unwrap();
+            match this.get(&id) {
+                Some(pending) if pending.deadline > Instant::now() => Expiry::Later(pending.deadline),
+                Some(_) => this.remove(&id).map(Expiry::Expired).unwrap_or(Expiry::Gone),
+                None => Expiry::Gone,
+            }
+        })
+        .await
+        .unwrap_or(Expiry::Gone)
+    }
+    /// Requests waiting on each service instance.
+    async fn in_flight(&self) -> HashMap<u64, usize> {
+        let this = self.clone();
+        spawn_blocking(move || {
+            let mut counts = HashMap::new();
+            for pending in this.read().unwrap().values() {
+                *counts.entry(pending.instance).or_default() += 1;
+            }
+            counts
+        })
+        .await
+        .unwrap_or_default()
+    }
+}
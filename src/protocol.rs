@@ -0,0 +1,306 @@
+use crate::frame::Payload;
+use crate::hub::Lag;
+use crate::schema::Schema;
+use crate::service::ServiceType;
+use anyhow::{Error, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, from_str, to_string, to_vec};
+use tungstenite::Message;
+
+pub const VERSION: u32 = 1;
+
+/// Wire format of a connection, picked by the handshake it opens with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// `&service#tag::payload` strings, a bare serial or `+name::type[::timeout_ms[::schema]]` to start.
+    /// `~service#tag` cancels what a client still waits on from that service and tag.
+    /// `-service#tag` ends a subscription, `-#owner` every subscription tagged `owner` or `owner-..`.
+    /// `&service#tag::pattern` subscribes to matching topics only, they arrive as `service#tag@topic::payload`
+    /// and services send them as `@topic::payload`, or `=topic::payload` to retain them.
+    /// `*service#tag::pattern` subscribes to request services too, where a payload would be a request.
+    /// Streamed replies mark chunks with a leading `>`, the same header without a payload ends them.
+    Legacy,
+    /// One [`Envelope`] per message, `hello` or `register` to start.
+    #[default]
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Hello,
+    Register,
+    Ok,
+    Request,
+    Subscribe,
+    /// Ends the subscription to `service` with `tag`, or without a service all those `tag` owns.
+    Unsubscribe,
+    Cancel,
+    Reply,
+    /// Part of a streamed reply, more follow until `end`.
+    Chunk,
+    End,
+    Broadcast,
+    Error,
+}
+
+/// Text frames hold the json alone, binary frames the json, a newline and then the raw payload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub kind: Kind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_type: Option<ServiceType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Milliseconds, on a request or on a service's registration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<Schema>,
+    /// What a broadcast is about, or the pattern of the topics a subscription wants.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Keep this broadcast as its topic's current value for clients subscribing later.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub retain: bool,
+    /// What a broadcast service wants done with subscribers that fall behind.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lag: Option<Lag>,
+    #[serde(skip)]
+    pub bytes: Option<Bytes>,
+}
+
+impl Envelope {
+    pub fn new(kind: Kind) -> Self {
+        Self {
+            kind,
+            version: None,
+            serial: None,
+            service: None,
+            service_type: None,
+            id: None,
+            tag: None,
+            payload: None,
+            error: None,
+            timeout: None,
+            schema: None,
+            topic: None,
+            retain: false,
+            lag: None,
+            bytes: None,
+        }
+    }
+    pub fn error(id: Option<u64>, e: impl Into<String>) -> Self {
+        Self {
+            id,
+            error: Some(e.into()),
+            ..Self::new(Kind::Error)
+        }
+    }
+    pub fn decode(msg: &Message) -> Result<Self> {
+        match msg {
+            Message::Text(text) => Ok(from_str(text.as_str())?),
+            Message::Binary(bytes) => {
+                let Some(at) = bytes.iter().position(|b| *b == b'\n') else {
+                    return Err(Error::msg("Invalid Envelope"));
+                };
+                let mut envelope: Self = from_slice(&bytes[..at])?;
+                envelope.bytes = Some(bytes.slice(at + 1..));
+                Ok(envelope)
+            }
+            _ => Err(Error::msg("Invalid Envelope")),
+        }
+    }
+    pub fn encode(&self) -> Message {
+        match &self.bytes {
+            // serde_json escapes newlines inside strings, so the first one ends the header
+            Some(bytes) => {
+                let mut frame = to_vec(self).unwrap_or_default();
+                frame.push(b'\n');
+                frame.extend_from_slice(bytes);
+                Message::binary(frame)
+            }
+            None => Message::text(to_string(self).unwrap_or_default()),
+        }
+    }
+    pub fn with_payload(mut self, payload: Payload) -> Self {
+        match payload {
+            Payload::Text(text) => self.payload = Some(text),
+            Payload::Binary(bytes) => self.bytes = Some(bytes),
+        }
+        self
+    }
+    /// `bytes` wins over `payload` when both are set.
+    pub fn take_payload(&mut self) -> Option<Payload> {
+        match (self.bytes.take(), self.payload.take()) {
+            (Some(bytes), _) => Some(Payload::Binary(bytes)),
+            (None, Some(text)) => Some(Payload::Text(text)),
+            (None, None) => None,
+        }
+    }
+    /// `None` unless this is a handshake of `kind`, an error if it is not for the current [`VERSION`].
+    pub fn handshake(handshake: &str, kind: Kind) -> Option<Result<Self>> {
+        let envelope: Self = from_str(handshake).ok()?;
+        if envelope.kind != kind {
+            return None;
+        }
+        Some(match envelope.version {
+            Some(VERSION) => Ok(envelope),
+            Some(v) => Err(Error::msg(format!("Unsupported Protocol Version {v}"))),
+            None => Err(Error::msg("Protocol Version Unspecified")),
+        })
+    }
+}
+
+/// What a service sent back for a request.
+#[derive(Debug, Clone)]
+pub enum Answer {
+    Reply(Payload),
+    Error(String),
+    Chunk(Payload),
+    End,
+}
+
+impl Answer {
+    /// Anything but a chunk completes the request.
+    pub fn is_final(&self) -> bool {
+        !matches!(self, Self::Chunk(_))
+    }
+}
+
+impl Protocol {
+    /// Json handshakes are objects, anything else is a legacy one.
+    pub fn detect(handshake: &str) -> Self {
+        if handshake.starts_with('{') {
+            Self::Json
+        } else {
+            Self::Legacy
+        }
+    }
+    pub fn ok(&self) -> Message {
+        match self {
+            Self::Legacy => Message::text("@Ok"),
+            Self::Json => Envelope {
+                version: Some(VERSION),
+                ..Envelope::new(Kind::Ok)
+            }
+            .encode(),
+        }
+    }
+    pub fn error(&self, id: Option<u64>, e: impl Into<String>) -> Message {
+        match self {
+            Self::Legacy => Message::text(format!("!{}", e.into())),
+            Self::Json => Envelope::error(id, e).encode(),
+        }
+    }
+}
+
+/// Reads the server's answer to a handshake in either protocol.
+pub fn accepted(reply: &str) -> Result<()> {
+    if reply == "@Ok" {
+        return Ok(());
+    }
+    if let Ok(envelope) = from_str::<Envelope>(reply) {
+        return match envelope.kind {
+            Kind::Ok => Ok(()),
+            _ => Err(Error::msg(envelope.error.unwrap_or_default())),
+        };
+    }
+    Err(Error::msg(String::from(reply.strip_prefix("!").unwrap_or(reply))))
+}
+
+/// `#tag`, the legacy format keeps the hash in front of tags.
+pub fn legacy_tag(tag: Option<&str>) -> String {
+    tag.map(|t| format!("#{t}")).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_the_protocol_from_the_handshake() {
+        assert_eq!(Protocol::detect(r#"{"kind":"hello","version":1}"#), Protocol::Json);
+        assert_eq!(Protocol::detect("R58M123"), Protocol::Legacy);
+        assert_eq!(Protocol::detect("+mpd::request"), Protocol::Legacy);
+        assert_eq!(Protocol::detect(""), Protocol::Legacy);
+    }
+
+    #[test]
+    fn text_envelope_round_trips() {
+        let envelope = Envelope {
+            id: Some(7),
+            tag: Some(String::from("ui")),
+            ..Envelope::new(Kind::Request)
+        }
+        .with_payload(Payload::Text(String::from("a::b\nc")));
+        let msg = envelope.encode();
+        assert!(msg.is_text());
+        let mut decoded = Envelope::decode(&msg).unwrap();
+        assert_eq!(decoded.kind, Kind::Request);
+        assert_eq!(decoded.id, Some(7));
+        assert_eq!(decoded.tag.as_deref(), Some("ui"));
+        assert!(matches!(decoded.take_payload(), Some(Payload::Text(text)) if text == "a::b\nc"));
+    }
+
+    #[test]
+    fn binary_envelope_keeps_newlines_in_the_payload() {
+        let envelope = Envelope {
+            payload: Some(String::from("line\nbreak")),
+            ..Envelope::new(Kind::Reply)
+        }
+        .with_payload(Payload::Binary(Bytes::from_static(b"\n\x00\n")));
+        let msg = envelope.encode();
+        assert!(msg.is_binary());
+        let mut decoded = Envelope::decode(&msg).unwrap();
+        assert_eq!(decoded.payload.as_deref(), Some("line\nbreak"));
+        // bytes win over the text payload
+        assert!(matches!(decoded.take_payload(), Some(Payload::Binary(bytes)) if &bytes[..] == b"\n\x00\n"));
+    }
+
+    #[test]
+    fn empty_payloads() {
+        let mut decoded = Envelope::decode(&Envelope::new(Kind::End).encode()).unwrap();
+        assert!(decoded.take_payload().is_none());
+        let msg = Envelope::new(Kind::Reply).with_payload(Payload::Binary(Bytes::new())).encode();
+        let mut decoded = Envelope::decode(&msg).unwrap();
+        assert!(matches!(decoded.take_payload(), Some(Payload::Binary(bytes)) if bytes.is_empty()));
+    }
+
+    #[test]
+    fn rejects_malformed_envelopes() {
+        assert!(Envelope::decode(&Message::text("&mpd::status")).is_err());
+        assert!(Envelope::decode(&Message::binary(br#"{"kind":"reply"}"#.to_vec())).is_err());
+        assert!(Envelope::decode(&Message::text(r#"{"kind":"nope"}"#)).is_err());
+    }
+
+    #[test]
+    fn handshake_checks_kind_and_version() {
+        assert!(Envelope::handshake(r#"{"kind":"hello","version":1}"#, Kind::Hello).unwrap().is_ok());
+        assert!(Envelope::handshake(r#"{"kind":"hello","version":2}"#, Kind::Hello).unwrap().is_err());
+        assert!(Envelope::handshake(r#"{"kind":"hello"}"#, Kind::Hello).unwrap().is_err());
+        assert!(Envelope::handshake(r#"{"kind":"register","version":1}"#, Kind::Hello).is_none());
+        assert!(Envelope::handshake("R58M123", Kind::Hello).is_none());
+    }
+
+    #[test]
+    fn reads_handshake_answers_of_both_protocols() {
+        assert!(accepted("@Ok").is_ok());
+        assert!(accepted(r#"{"kind":"ok","version":1}"#).is_ok());
+        assert_eq!(accepted("!Service already registered").unwrap_err().to_string(), "Service already registered");
+        assert_eq!(accepted(r#"{"kind":"error","error":"Nope"}"#).unwrap_err().to_string(), "Nope");
+    }
+}
@@ -0,0 +1,338 @@
+use serde::de::value::Error;
+use serde::de::{
+    DeserializeOwned, DeserializeSeed, Deserializer, EnumAccess, Error as _, IntoDeserializer, MapAccess,
+    SeqAccess, VariantAccess, Visitor,
+};
+use serde::{Deserialize, Serialize, forward_to_deserialize_any};
+use std::cell::Cell;
+
+/// What a service answers to, published at registration so pages and `ctl` can look it up.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Schema {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<Command>,
+}
+
+/// A request of the form `name arg arg ..`, arguments separated by whitespace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Command {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<Arg>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Arg {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub arg_type: ArgType,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgType {
+    String,
+    Number,
+    Bool,
+    /// Everything left of the request, spaces included.
+    Rest,
+}
+
+impl Schema {
+    pub fn new(description: impl Into<String>) -> Self {
+        Self {
+            description: Some(description.into()),
+            commands: Vec::new(),
+        }
+    }
+    pub fn command(mut self, command: Command) -> Self {
+        self.commands.push(command);
+        self
+    }
+    /// One command per variant of the command enum `C` with its fields as arguments,
+    /// the way [`RequestService::serve_commands`](crate::service::RequestService::serve_commands) reads them.
+    pub fn of<C: DeserializeOwned>(description: impl Into<String>) -> Self {
+        let variants = Cell::new(None);
+        let _ = C::deserialize(Variants(&variants));
+        let commands = variants
+            .take()
+            .unwrap_or_default()
+            .iter()
+            .map(|variant| {
+                let mut args = Vec::new();
+                let _ = C::deserialize(Trace {
+                    variant,
+                    args: &mut args,
+                });
+                // the last argument takes the rest of the line
+                if let Some(last) = args.last_mut()
+                    && last.arg_type == ArgType::String
+                {
+                    last.arg_type = ArgType::Rest;
+                }
+                Command {
+                    name: String::from(*variant),
+                    description: None,
+                    args,
+                }
+            })
+            .collect();
+        Self {
+            description: Some(description.into()).filter(|d| !d.is_empty()),
+            commands,
+        }
+    }
+    pub fn describe(mut self, command: &str, description: impl Into<String>) -> Self {
+        if let Some(command) = self.commands.iter_mut().find(|c| c.name == command) {
+            command.description = Some(description.into());
+        }
+        self
+    }
+}
+
+impl Command {
+    pub fn new(name: impl Into<String>, description: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            description: Some(description.into()),
+            args: Vec::new(),
+        }
+    }
+    pub fn arg(mut self, name: impl Into<String>, arg_type: ArgType) -> Self {
+        self.args.push(Arg {
+            name: name.into(),
+            arg_type,
+            optional: false,
+        });
+        self
+    }
+    pub fn optional(mut self, name: impl Into<String>, arg_type: ArgType) -> Self {
+        self.args.push(Arg {
+            name: name.into(),
+            arg_type,
+            optional: true,
+        });
+        self
+    }
+}
+
+/// Stops at the first enum and keeps its variant names.
+struct Variants<'a>(&'a Cell<Option<&'static [&'static str]>>);
+
+impl<'de> Deserializer<'de> for Variants<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error::custom("Commands must be an enum"))
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.0.set(Some(variants));
+        Err(Error::custom("Traced"))
+    }
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Walks one variant and notes the type of every field it asks for.
+struct Trace<'a> {
+    variant: &'static str,
+    args: &'a mut Vec<Arg>,
+}
+
+impl<'de> Deserializer<'de> for Trace<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error::custom("Commands must be an enum"))
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self)
+    }
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de, 'a> EnumAccess<'de> for Trace<'a> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.variant))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Trace<'_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(TraceArg {
+            name: String::from("value"),
+            optional: false,
+            args: self.args,
+        })
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(TraceFields {
+            names: &[],
+            len,
+            at: 0,
+            args: self.args,
+        })
+    }
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_map(TraceFields {
+            names: fields,
+            len: fields.len(),
+            at: 0,
+            args: self.args,
+        })
+    }
+}
+
+struct TraceFields<'a> {
+    names: &'static [&'static str],
+    len: usize,
+    at: usize,
+    args: &'a mut Vec<Arg>,
+}
+
+impl TraceFields<'_> {
+    fn next(&mut self) -> TraceArg<'_> {
+        let name = match self.names.get(self.at) {
+            Some(name) => String::from(*name),
+            None => format!("arg{}", self.at + 1),
+        };
+        self.at += 1;
+        TraceArg {
+            name,
+            optional: false,
+            args: self.args,
+        }
+    }
+}
+
+impl<'de> SeqAccess<'de> for TraceFields<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.at == self.len {
+            return Ok(None);
+        }
+        seed.deserialize(self.next()).map(Some)
+    }
+}
+
+impl<'de> MapAccess<'de> for TraceFields<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        if self.at == self.len {
+            return Ok(None);
+        }
+        let name = IntoDeserializer::<Error>::into_deserializer(self.names[self.at]);
+        seed.deserialize(name).map(Some)
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(self.next())
+    }
+}
+
+struct TraceArg<'a> {
+    name: String,
+    optional: bool,
+    args: &'a mut Vec<Arg>,
+}
+
+impl TraceArg<'_> {
+    fn push(self, arg_type: ArgType) {
+        self.args.push(Arg {
+            name: self.name,
+            arg_type,
+            optional: self.optional,
+        });
+    }
+}
+
+macro_rules! trace_number {
+    ($($deserialize:ident => $visit:ident,)*) => {
+        $(
+            fn $deserialize<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                self.push(ArgType::Number);
+                visitor.$visit(Default::default())
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for TraceArg<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.push(ArgType::String);
+        visitor.visit_str("")
+    }
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.push(ArgType::Bool);
+        visitor.visit_bool(false)
+    }
+    trace_number! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(Self {
+            optional: true,
+            ..self
+        })
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.push(ArgType::String);
+        let first = variants.first().copied().unwrap_or_default();
+        visitor.visit_enum(IntoDeserializer::<Error>::into_deserializer(first))
+    }
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct newtype_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
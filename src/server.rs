@@ -3,24 +3,32 @@ use anyhow::{Error, Result};
 use bytes::Bytes;
 use futures::{
     prelude::stream::{SplitSink, SplitStream},
-    Sink, SinkExt, Stream, StreamExt,
+    SinkExt, StreamExt,
 };
 use std::collections::{BTreeMap, HashMap};
-use std::io::{BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-use tokio::task::spawn_blocking;
-use tokio::time::interval;
+use tokio::sync::{Mutex as AsyncMutex, Notify};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{interval, sleep_until, Instant};
 use tokio_tungstenite::accept_async;
 use tokio_tungstenite::WebSocketStream;
-use tungstenite::protocol::{frame::Frame, frame::Utf8Bytes, Message};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, Message};
 
-use crate::client::{Client, ClientHandler, ClientMap};
-use crate::service::{RequestHandler, Service, ServiceMap, ServiceMapExt, ServiceType};
+use crate::admin::AdminHandler;
+use crate::client::{ClientHandler, ClientMap};
+use crate::client::ClientMapExt;
+use crate::hub::{Hub, Lag};
+use crate::pending::{Expiry, Pending, PendingMap, PendingMapExt};
+use crate::protocol::Answer;
+use crate::service::{
+    BroadcastHandler, Conflict, Dispatch, RequestHandler, Service, ServiceMap, ServiceMapExt, ServiceType,
+};
 
 pub struct Server {
     listener: TcpListener,
@@ -31,73 +39,232 @@ pub struct Server {
 pub struct ConnectionMap {
     pub client_map: ClientMap,
     pub service_map: ServiceMap,
+    pub pending: PendingMap,
+    request_timeout: Arc<RwLock<Duration>>,
+    broadcast: Arc<RwLock<(Lag, usize)>>,
+    liveness_timeout: Arc<RwLock<Duration>>,
+    conflict: Arc<RwLock<Conflict>>,
+    dispatch: Arc<RwLock<Dispatch>>,
+}
+
+impl ConnectionMap {
+    pub fn request_timeout(&self) -> Duration {
+        *self.request_timeout.read().unwrap()
+    }
+    pub fn conflict(&self) -> Conflict {
+        *self.conflict.read().unwrap()
+    }
+    /// A hub for a service that just registered, with its own lag policy if it asked for one.
+    pub fn hub(&self, service: &Service) -> Arc<Hub> {
+        let (lag, capacity) = *self.broadcast.read().unwrap();
+        Arc::new(Hub::new(&service.name, service.lag.unwrap_or(lag), capacity))
+    }
+    /// The instance of `name` the next request goes to.
+    pub async fn route(&self, name: &str) -> Result<Service> {
+        let dispatch = *self.dispatch.read().unwrap();
+        match dispatch {
+            Dispatch::RoundRobin => self.service_map.get(name).await,
+            Dispatch::LeastInFlight => {
+                let in_flight = self.pending.in_flight().await;
+                self.service_map
+                    .instances(name)
+                    .await
+                    .into_iter()
+                    .min_by_key(|s| in_flight.get(&s.instance).copied().unwrap_or_default())
+                    .ok_or_else(|| Error::msg("Invalid Service"))
+            }
+        }
+    }
+    /// Fails request `id` with `Request Timed Out` once its deadline passes, chunks push it back.
+    pub fn watch(&self, id: u64, deadline: Instant) {
+        let connection_map = self.clone();
+        tokio::spawn(async move {
+            let mut deadline = deadline;
+            loop {
+                sleep_until(deadline).await;
+                match connection_map.pending.expire(id).await {
+                    Expiry::Expired(pending) => {
+                        connection_map.fail(&pending, "Request Timed Out").await;
+                        break;
+                    }
+                    Expiry::Later(later) => deadline = later,
+                    Expiry::Gone => break,
+                }
+            }
+        });
+    }
+    /// Hands what an instance that went away still owes to the rest of its pool.
+    /// Streamed requests and those with nowhere to go fail with `Service Disconnected`.
+    pub async fn drain(&self, name: &str, instance: u64) {
+        let owed = self.pending.drain(move |p| p.instance == instance).await;
+        for (id, mut pending) in owed {
+            if !pending.streamed
+                && let Ok(service) = self.route(name).await
+                && service.service_type == ServiceType::Request
+            {
+                pending.instance = service.instance;
+                self.pending.restore(id, pending.clone()).await;
+                if service.request(id, &pending, pending.payload.clone()).is_ok() {
+                    println!("Drain => {name} :: {instance} -> {}", service.instance);
+                    continue;
+                }
+                self.pending.remove(id).await;
+            }
+            self.fail(&pending, "Service Disconnected").await;
+        }
+    }
+    /// Answers a request in place of its service, the client gets it like any error reply.
+    pub async fn fail(&self, pending: &Pending, e: &str) {
+        if let Some(client) = self
+            .client_map
+            .get(pending.serial.clone(), pending.client)
+            .await
+        {
+            let _ = client.reply(pending, Answer::Error(String::from(e)));
+        }
+    }
 }
 
 impl Server {
-    pub async fn new(port: u16) -> Result<Self> {
-        let listener = TcpListener::bind(format!("localhost:{port}")).await?;
+    pub async fn new(address: &str, port: u16) -> Result<Self> {
+        let listener = TcpListener::bind(format!("{address}:{port}")).await?;
         let client_map = Arc::new(RwLock::new(HashMap::new()));
         let service_map = Arc::new(RwLock::new(HashMap::new()));
         let connection_map = ConnectionMap {
             client_map,
             service_map,
+            pending: Arc::new(RwLock::new(BTreeMap::new())),
+            request_timeout: Arc::new(RwLock::new(Duration::from_secs(30))),
+            broadcast: Arc::new(RwLock::new((Lag::default(), 64))),
+            liveness_timeout: Arc::new(RwLock::new(Duration::from_secs(75))),
+            conflict: Arc::new(RwLock::new(Conflict::default())),
+            dispatch: Arc::new(RwLock::new(Dispatch::default())),
         };
-        println!("Server => Bind :: localhost:{port}");
+        println!("Server => Bind :: {address}:{port}");
         Ok(Self {
             listener,
             connection_map,
         })
     }
+    /// Keeps listening where it was if the new address can't be bound.
+    pub async fn rebind(&mut self, address: &str, port: u16) -> Result<()> {
+        let old = self.listener.local_addr()?;
+        if old.port() == port {
+            // a port is only listened on once, the old listener lets go of it first
+            self.listener = TcpListener::bind((old.ip(), 0)).await?;
+            match TcpListener::bind(format!("{address}:{port}")).await {
+                Ok(listener) => self.listener = listener,
+                Err(e) => {
+                    self.listener = TcpListener::bind(old).await?;
+                    return Err(e.into());
+                }
+            }
+        } else {
+            self.listener = TcpListener::bind(format!("{address}:{port}")).await?;
+        }
+        println!("Server => Rebind :: {address}:{port}");
+        Ok(())
+    }
+    /// Applies to requests sent from now on that neither the service nor the client gave a timeout.
+    pub fn set_request_timeout(&self, timeout: Duration) {
+        *self.connection_map.request_timeout.write().unwrap() = timeout;
+    }
+    /// Applies to broadcast services registering from now on, each subscriber queues up to `buffer` messages.
+    pub fn set_broadcast(&self, lag: Lag, buffer: usize) {
+        *self.connection_map.broadcast.write().unwrap() = (lag, buffer);
+    }
+    /// Applies to connections made from now on, zero keeps them open however long they stay silent.
+    pub fn set_liveness_timeout(&self, timeout: Duration) {
+        *self.connection_map.liveness_timeout.write().unwrap() = timeout;
+    }
+    /// Applies to services registering from now on.
+    pub fn set_service_conflict(&self, conflict: Conflict) {
+        *self.connection_map.conflict.write().unwrap() = conflict;
+    }
+    /// How requests spread over a pool, applies right away.
+    pub fn set_pool_dispatch(&self, dispatch: Dispatch) {
+        *self.connection_map.dispatch.write().unwrap() = dispatch;
+    }
     pub async fn handle(&self) {
         if let Ok((raw_stream, addr)) = self.listener.accept().await {
             println!("Server => Connect :: {addr}");
             let connection_map = self.connection_map.clone();
             tokio::spawn(async move {
-                if let Err(e) = Self::accept_ws(raw_stream, connection_map).await {
+                if let Err(e) = Self::accept_ws(raw_stream, addr, connection_map).await {
                     eprintln!("{e}");
                 }
             });
         }
-        {
-            println!(
-                "ClientMap :: {:#?}",
-                self.connection_map.client_map.read().unwrap()
-            );
-            println!(
-                "ServiceMap :: {:#?}",
-                self.connection_map.service_map.read().unwrap()
-            );
-        }
     }
-    async fn accept_ws(raw_stream: TcpStream, connection_map: ConnectionMap) -> Result<()> {
+    async fn accept_ws(raw_stream: TcpStream, peer: SocketAddr, connection_map: ConnectionMap) -> Result<()> {
         match accept_async(raw_stream).await {
             Ok(ws) => {
                 let (sink, stream) = ws.split();
-                let mut connection = Connection::new(stream, sink).await;
+                let liveness = *connection_map.liveness_timeout.read().unwrap();
+                let mut connection = Connection::new(stream, sink, liveness).await;
                 loop {
                     if let Ok(Message::Text(req)) = connection.read().await {
-                        if let Some(service) = Service::from_req(req.as_str(), &connection)? {
-                            if let Err(e) = connection_map
+                        if let Some(mut service) = Service::from_req(req.as_str(), &connection)? {
+                            service.hub = Some(connection_map.hub(&service));
+                            let service = match connection_map
                                 .service_map
-                                    .insert(&service.name, service.clone())
-                                    .await
+                                .insert(service.clone(), connection_map.conflict())
+                                .await
                             {
-                                service.send(format!("!{e}").into())?;
-                                return Ok(());
+                                Ok((service, replaced)) => {
+                                    for old in replaced {
+                                        println!("Replace => {} :: {}", old.name, old.instance);
+                                        old.replaced();
+                                    }
+                                    service
+                                }
+                                Err(e) => {
+                                    service.send(service.protocol.error(None, e.to_string()))?;
+                                    return Ok(());
+                                }
                             };
-                            println!("Service => {} :: {:?}", service.name, service.service_type);
-                            if let ServiceType::Request = service.service_type {
-                                let mut handler = RequestHandler::new(service, connection_map)?;
-                                tokio::spawn(async move {
-                                    loop {
-                                        if let Err(e) = handler.handle().await {
-                                            eprintln!("{e}");
-                                            break;
+                            service.send(service.protocol.ok())?;
+                            println!(
+                                "Service => {} :: {:?}@{}",
+                                service.name, service.service_type, service.instance
+                            );
+                            match service.service_type {
+                                ServiceType::Request => {
+                                    let mut handler = RequestHandler::new(service, connection_map)?;
+                                    tokio::spawn(async move {
+                                        loop {
+                                            if let Err(e) = handler.handle().await {
+                                                eprintln!("{e}");
+                                                break;
+                                            }
                                         }
-                                    }
-                                });
+                                    });
+                                }
+                                ServiceType::Broadcast => {
+                                    let mut handler = BroadcastHandler::new(service, connection_map)?;
+                                    tokio::spawn(async move {
+                                        loop {
+                                            if let Err(e) = handler.handle().await {
+                                                eprintln!("{e}");
+                                                break;
+                                            }
+                                        }
+                                    });
+                                }
                             }
                             break;
+                        } else if let Some(mut admin_handler) =
+                            AdminHandler::from_req(req.as_str(), peer, &connection, &connection_map)?
+                        {
+                            tokio::spawn(async move {
+                                loop {
+                                    if let Err(e) = admin_handler.handle().await {
+                                        eprintln!("{e}");
+                                        break;
+                                    }
+                                }
+                            });
+                            break;
                         } else if let Ok(mut client_handler) =
                             ClientHandler::from_req(req.as_str(), &connection, &connection_map).await
                         {
@@ -108,6 +275,7 @@ impl Server {
                                         break;
                                     }
                                 }
+                                client_handler.teardown().await;
                             });
                             break;
                         }
@@ -120,73 +288,135 @@ impl Server {
     }
 }
 
-#[derive(Debug)]
+/// Clones share one inbox, only the handler that owns the connection reads it.
+#[derive(Debug, Clone)]
 pub struct Connection {
     pub sender: UnboundedSender<Message>,
-    broadcast: broadcast::Sender<Message>,
-    receiver: broadcast::Receiver<Message>,
-}
-
-impl Clone for Connection {
-    fn clone(&self) -> Self {
-        Self {
-            sender: self.sender.clone(),
-            broadcast: self.broadcast.clone(),
-            receiver: self.broadcast.subscribe(),
-        }
-    }
+    /// Everything the peer sent, in order and without a limit.
+    inbox: Arc<AsyncMutex<UnboundedReceiver<Message>>>,
+    /// Puts a close in the inbox as if the peer had left.
+    hangup: UnboundedSender<Message>,
+    /// Messages sent but not yet written to the socket.
+    backlog: Arc<AtomicUsize>,
+    flushed: Arc<Notify>,
 }
 
 impl Connection {
+    /// A connection that stays silent for longer than `liveness` is closed as if the peer had left.
     pub async fn new<S>(
         stream: SplitStream<WebSocketStream<S>>,
         mut sink: SplitSink<WebSocketStream<S>, Message>,
+        liveness: Duration,
     ) -> Self where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
         let (tx, mut rx) = unbounded_channel::<Message>();
-        let (b, r) = broadcast::channel(64);
+        let (hangup, inbox) = unbounded_channel::<Message>();
+        let (backlog, flushed) = (Arc::new(AtomicUsize::new(0)), Arc::new(Notify::new()));
+        let (written, drained) = (backlog.clone(), flushed.clone());
         tokio::spawn(async move {
-            loop {
-                if let Some(msg) = rx.recv().await {
-                    let _ = sink.send(msg).await;
+            while let Some(msg) = rx.recv().await {
+                let _ = sink.send(msg).await;
+                if written.fetch_sub(1, Ordering::AcqRel) == 1 {
+                    drained.notify_waiters();
                 }
             }
+            let _ = sink.close().await;
         });
-        let sndr = b.clone();
-        tokio::spawn(async move {
+        let sndr = hangup.clone();
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        let seen = last_seen.clone();
+        let reader = tokio::spawn(async move {
             stream
                 .for_each(|msg| async {
                     if let Ok(msg) = msg {
-                        if let Message::Text(ref text) = msg {
-                            if text.as_str() == "?" {
-                                // println!("ping");
-                                return;
-                            }
+                        *seen.lock().unwrap() = Instant::now();
+                        if let Message::Text(ref text) = msg
+                            && text.as_str() == "?"
+                        {
+                            // println!("ping");
+                            return;
+                        }
+                        if let Message::Ping(_) | Message::Pong(_) = msg {
+                            return;
                         }
                         let _ = sndr.send(msg);
                     }
                 })
                 .await;
+            // the peer may vanish without a close frame, handlers still need to clean up
+            let _ = sndr.send(Message::Close(None));
         });
-        let ping_tx = tx.clone();
+        // weak so pinging alone doesn't keep the connection open once every handle is dropped
+        let ping_tx = tx.downgrade();
+        let ping_backlog = backlog.clone();
+        let gone = hangup.clone();
+        // often enough that a live peer always answers within `liveness`
+        let every = match liveness.is_zero() {
+            true => Duration::from_secs(30),
+            false => (liveness / 2).min(Duration::from_secs(30)),
+        };
         tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_secs(30)).await;
-            let mut ping = interval(Duration::from_secs(30));
+            tokio::time::sleep(every).await;
+            let mut ping = interval(every);
             loop {
                 ping.tick().await;
-                let _ = ping_tx.send("?".into());
+                let Some(ping_tx) = ping_tx.upgrade() else {
+                    break;
+                };
+                let silent = last_seen.lock().unwrap().elapsed();
+                if !liveness.is_zero() && silent > liveness {
+                    println!("Dead => Silent :: {silent:?}");
+                    reader.abort();
+                    let _ = gone.send(Message::Close(None));
+                    let _ = ping_tx.send(Message::Close(None));
+                    break;
+                }
+                // legacy clients look for the `?`, the pong to the ping frame proves the peer is there
+                for msg in [Message::text("?"), Message::Ping(Bytes::new())] {
+                    ping_backlog.fetch_add(1, Ordering::AcqRel);
+                    if ping_tx.send(msg).is_err() {
+                        ping_backlog.fetch_sub(1, Ordering::AcqRel);
+                    }
+                }
             }
         });
         Self {
             sender: tx,
-            broadcast: b,
-            receiver: r,
+            inbox: Arc::new(AsyncMutex::new(inbox)),
+            hangup,
+            backlog,
+            flushed,
         }
     }
     pub async fn read(&mut self) -> Result<Message> {
-        Ok(self.receiver.recv().await?)
+        self.inbox
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| Error::msg("Connection closed"))
     }
     pub fn send(&self, msg: Message) -> Result<()> {
-        Ok(self.sender.send(msg)?)
+        self.backlog.fetch_add(1, Ordering::AcqRel);
+        self.sender.send(msg).map_err(|e| {
+            self.backlog.fetch_sub(1, Ordering::AcqRel);
+            Error::from(e)
+        })
+    }
+    /// Hangs up on the peer with `reason`, handlers reading from the connection see it close.
+    pub fn close(&self, reason: &str) {
+        let _ = self.send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: reason.into(),
+        })));
+        let _ = self.hangup.send(Message::Close(None));
+    }
+    /// Resolves once everything sent so far has been written to the socket.
+    pub async fn flushed(&self) {
+        let flushed = self.flushed.notified();
+        if self.backlog.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        flushed.await
     }
 }
 
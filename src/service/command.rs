@@ -0,0 +1,325 @@
+use anyhow::Result;
+use serde::de::value::Error;
+use serde::de::{
+    DeserializeOwned, DeserializeSeed, Deserializer, EnumAccess, Error as _, IntoDeserializer, MapAccess,
+    SeqAccess, VariantAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+use std::str::FromStr;
+
+/// Reads `name arg arg ..` into a variant of the command enum `C`, or a json object when the request is one.
+/// Arguments fill the variant's fields in order and the last one takes the rest of the line.
+pub fn parse<C: DeserializeOwned>(request: &str) -> Result<C> {
+    let request = request.trim();
+    if request.starts_with('{') {
+        return Ok(serde_json::from_str(request)?);
+    }
+    Ok(C::deserialize(Line(request))?)
+}
+
+/// The name of the command `request` holds, `None` unless it parses into `C`.
+pub fn name<C: DeserializeOwned>(request: &str) -> Option<String> {
+    parse::<C>(request).ok()?;
+    let request = request.trim();
+    if request.starts_with('{') {
+        let command: serde_json::Map<String, serde_json::Value> = serde_json::from_str(request).ok()?;
+        return command.keys().next().cloned();
+    }
+    Some(String::from(next_token(request).0))
+}
+
+struct Line<'a>(&'a str);
+
+impl<'de> Deserializer<'de> for Line<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error::custom("Commands must be an enum"))
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self)
+    }
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de, 'a> EnumAccess<'de> for Line<'a> {
+    type Error = Error;
+    type Variant = Args<'a>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Args<'a>), Error> {
+        if self.0.is_empty() {
+            return Err(Error::custom("Command Unspecified"));
+        }
+        let (name, rest) = next_token(self.0);
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(name))?;
+        Ok((variant, Args(rest)))
+    }
+}
+
+/// What is left of the line after the command name.
+struct Args<'a>(&'a str);
+
+impl<'de> VariantAccess<'de> for Args<'_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        match self.0 {
+            "" => Ok(()),
+            rest => Err(Error::custom(format!("Unexpected Argument {rest}"))),
+        }
+    }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(Arg {
+            token: Some(self.0).filter(|t| !t.is_empty()),
+            name: String::from("value"),
+        })
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Fields {
+            rest: self.0,
+            names: &[],
+            len,
+            at: 0,
+        })
+    }
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_map(Fields {
+            rest: self.0,
+            names: fields,
+            len: fields.len(),
+            at: 0,
+        })
+    }
+}
+
+struct Fields<'a> {
+    rest: &'a str,
+    names: &'static [&'static str],
+    len: usize,
+    at: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn next(&mut self) -> Arg<'a> {
+        let name = match self.names.get(self.at) {
+            Some(name) => String::from(*name),
+            None => format!("arg{}", self.at + 1),
+        };
+        self.at += 1;
+        let token = if self.at == self.len {
+            std::mem::take(&mut self.rest)
+        } else {
+            let (token, rest) = next_token(self.rest);
+            self.rest = rest;
+            token
+        };
+        Arg {
+            token: Some(token).filter(|t| !t.is_empty()),
+            name,
+        }
+    }
+}
+
+impl<'de> SeqAccess<'de> for Fields<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.at == self.len {
+            return Ok(None);
+        }
+        seed.deserialize(self.next()).map(Some)
+    }
+}
+
+impl<'de> MapAccess<'de> for Fields<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        if self.at == self.len {
+            return Ok(None);
+        }
+        let name = IntoDeserializer::<Error>::into_deserializer(self.names[self.at]);
+        seed.deserialize(name).map(Some)
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(self.next())
+    }
+}
+
+/// One argument, `None` once the line ran out.
+struct Arg<'a> {
+    token: Option<&'a str>,
+    name: String,
+}
+
+impl<'a> Arg<'a> {
+    fn token(&self) -> Result<&'a str, Error> {
+        self.token
+            .ok_or_else(|| Error::custom(format!("Missing Argument {}", self.name)))
+    }
+    fn parse<T: FromStr>(&self) -> Result<T, Error> {
+        let token = self.token()?;
+        token
+            .parse()
+            .map_err(|_| Error::custom(format!("Invalid Argument {} :: {token}", self.name)))
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($deserialize:ident => $visit:ident,)*) => {
+        $(
+            fn $deserialize<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                visitor.$visit(self.parse()?)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Arg<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_str(self.token()?)
+    }
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.token {
+            Some(_) => visitor.visit_some(self),
+            None => visitor.visit_none(),
+        }
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(IntoDeserializer::<Error>::into_deserializer(self.token()?))
+    }
+    forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit unit_struct newtype_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+fn next_token(line: &str) -> (&str, &str) {
+    match line.split_once(char::is_whitespace) {
+        Some((token, rest)) => (token, rest.trim_start()),
+        None => (line, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Mode {
+        Fast,
+        Slow,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Command {
+        Status,
+        Volume(u8),
+        Say(String),
+        Seek(u32, Option<u32>),
+        Send { to: String, message: String },
+        Play { mode: Mode, times: Option<u16> },
+    }
+
+    fn parsed(request: &str) -> Command {
+        parse(request).unwrap()
+    }
+
+    fn error(request: &str) -> String {
+        parse::<Command>(request).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn unit_and_newtype_variants() {
+        assert_eq!(parsed("status"), Command::Status);
+        assert_eq!(parsed("  volume   42 "), Command::Volume(42));
+    }
+
+    #[test]
+    fn last_field_takes_the_rest_of_the_line() {
+        assert_eq!(parsed("say hello  there"), Command::Say(String::from("hello  there")));
+        assert_eq!(
+            parsed("send phone a::b c"),
+            Command::Send {
+                to: String::from("phone"),
+                message: String::from("a::b c"),
+            }
+        );
+    }
+
+    #[test]
+    fn optional_and_enum_fields() {
+        assert_eq!(parsed("seek 10"), Command::Seek(10, None));
+        assert_eq!(parsed("seek 10 20"), Command::Seek(10, Some(20)));
+        assert_eq!(parsed("play fast"), Command::Play { mode: Mode::Fast, times: None });
+        assert_eq!(parsed("play slow 3"), Command::Play { mode: Mode::Slow, times: Some(3) });
+    }
+
+    #[test]
+    fn json_requests() {
+        assert_eq!(parsed(r#"{"volume": 7}"#), Command::Volume(7));
+        assert_eq!(
+            parsed(r#"{"send": {"to": "phone", "message": "hi there"}}"#),
+            Command::Send {
+                to: String::from("phone"),
+                message: String::from("hi there"),
+            }
+        );
+    }
+
+    #[test]
+    fn errors_name_the_problem() {
+        assert_eq!(error(""), "Command Unspecified");
+        assert_eq!(error("   "), "Command Unspecified");
+        assert_eq!(error("status now"), "Unexpected Argument now");
+        assert_eq!(error("volume"), "Missing Argument value");
+        assert_eq!(error("volume loud"), "Invalid Argument value :: loud");
+        assert_eq!(error("send phone"), "Missing Argument message");
+        assert!(error("rewind").contains("unknown variant `rewind`"));
+        assert!(error("volume 300").contains("300"));
+    }
+
+    #[test]
+    fn names_only_known_commands() {
+        assert_eq!(name::<Command>("volume 3").as_deref(), Some("volume"));
+        assert_eq!(name::<Command>(r#"{"say": "hi"}"#).as_deref(), Some("say"));
+        assert_eq!(name::<Command>("rewind 3"), None);
+        assert_eq!(name::<Command>("volume loud"), None);
+    }
+}
@@ -0,0 +1,214 @@
+use super::REPLACED;
+use crate::protocol::accepted;
+use anyhow::{Error, Result};
+use futures::{
+    SinkExt, StreamExt,
+    prelude::stream::{SplitSink, SplitStream},
+};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::sync::watch;
+use tokio::time::{Instant, interval, sleep};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+use tungstenite::Message;
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+static PING_INTERVAL: Duration = Duration::from_secs(30);
+// the server pings every 30s too, so this long without a frame means the socket is dead
+static SILENCE_TIMEOUT: Duration = Duration::from_secs(75);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// Lost the server, trying to connect and register again.
+    Reconnecting,
+    /// Gave up for good, either reconnecting is off or the service was dropped.
+    Closed,
+}
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Service side of the websocket, re-registers under the same name whenever it drops.
+pub struct ServiceConnection {
+    pub sender: UnboundedSender<Message>,
+    receiver: UnboundedReceiver<Message>,
+    state: watch::Receiver<ConnectionState>,
+}
+
+impl ServiceConnection {
+    pub async fn connect(url: String, registration: String, reconnect: Option<Backoff>) -> Result<Self> {
+        let ws = register(&url, &registration).await?;
+        let (tx, outbound) = unbounded_channel::<Message>();
+        let (inbound, rx) = unbounded_channel::<Message>();
+        let (state_tx, state) = watch::channel(ConnectionState::Connected);
+        tokio::spawn(supervise(
+            url,
+            registration,
+            ws,
+            outbound,
+            inbound,
+            state_tx,
+            reconnect,
+        ));
+        Ok(Self {
+            sender: tx,
+            receiver: rx,
+            state,
+        })
+    }
+    /// `None` once the connection is closed for good.
+    pub fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.receiver.poll_recv(cx)
+    }
+    pub fn state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+}
+
+enum Pumped {
+    Dropped,
+    Shutdown,
+}
+
+async fn supervise(
+    url: String,
+    registration: String,
+    ws: WebSocket,
+    mut outbound: UnboundedReceiver<Message>,
+    inbound: UnboundedSender<Message>,
+    state: watch::Sender<ConnectionState>,
+    reconnect: Option<Backoff>,
+) {
+    let mut ws = Some(ws);
+    let mut delay = reconnect.as_ref().map(|b| b.initial).unwrap_or_default();
+    loop {
+        if let Some(ws) = ws.take() {
+            set_state(&state, ConnectionState::Connected);
+            let (sink, stream) = ws.split();
+            if let Pumped::Shutdown = pump(sink, stream, &mut outbound, &inbound).await {
+                break;
+            }
+            eprintln!("Service => Disconnected :: {registration}");
+        }
+        let Some(backoff) = &reconnect else {
+            break;
+        };
+        set_state(&state, ConnectionState::Reconnecting);
+        sleep(delay).await;
+        if inbound.is_closed() {
+            break;
+        }
+        match register(&url, &registration).await {
+            Ok(new) => {
+                println!("Service => Reconnected :: {registration}");
+                ws = Some(new);
+                delay = backoff.initial;
+            }
+            Err(e) => {
+                eprintln!("Service => Reconnect :: {registration} :: {e}");
+                delay = (delay * 2).min(backoff.max);
+            }
+        }
+    }
+    set_state(&state, ConnectionState::Closed);
+}
+
+fn set_state(state: &watch::Sender<ConnectionState>, new: ConnectionState) {
+    state.send_if_modified(|old| {
+        let changed = *old != new;
+        *old = new;
+        changed
+    });
+}
+
+async fn pump(
+    mut sink: SplitSink<WebSocket, Message>,
+    mut stream: SplitStream<WebSocket>,
+    outbound: &mut UnboundedReceiver<Message>,
+    inbound: &UnboundedSender<Message>,
+) -> Pumped {
+    let mut ping = interval(PING_INTERVAL);
+    ping.tick().await;
+    let mut last_seen = Instant::now();
+    loop {
+        select! {
+            msg = outbound.recv() => {
+                let Some(msg) = msg else {
+                    let _ = sink.close().await;
+                    return Pumped::Shutdown;
+                };
+                if sink.send(msg).await.is_err() {
+                    return Pumped::Dropped;
+                }
+            }
+            msg = stream.next() => {
+                last_seen = Instant::now();
+                match msg {
+                    Some(Ok(Message::Text(text))) if text.as_str() == "?" => {
+                        if sink.send(Message::text("?")).await.is_err() {
+                            return Pumped::Dropped;
+                        }
+                    }
+                    Some(Ok(Message::Close(Some(frame)))) if frame.reason.as_str() == REPLACED => {
+                        eprintln!("Service => {REPLACED}");
+                        return Pumped::Shutdown;
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        return Pumped::Dropped;
+                    }
+                    Some(Ok(msg)) => {
+                        if inbound.send(msg).is_err() {
+                            let _ = sink.close().await;
+                            return Pumped::Shutdown;
+                        }
+                    }
+                }
+            }
+            _ = inbound.closed() => {
+                let _ = sink.close().await;
+                return Pumped::Shutdown;
+            }
+            _ = ping.tick() => {
+                if last_seen.elapsed() > SILENCE_TIMEOUT
+                    || sink.send(Message::text("?")).await.is_err()
+                {
+                    return Pumped::Dropped;
+                }
+            }
+        }
+    }
+}
+
+/// Connects and registers, resolving only once the server has accepted the name.
+async fn register(url: &str, registration: &str) -> Result<WebSocket> {
+    let (mut ws, _) = connect_async(url).await?;
+    ws.send(Message::text(registration)).await?;
+    loop {
+        match ws.next().await {
+            Some(Ok(Message::Text(reply))) => match reply.as_str() {
+                "?" => continue,
+                reply => return accepted(reply).map(|_| ws),
+            },
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(Error::msg("Connection closed")),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use super::{Reply, Request, command};
+use crate::device::Serial;
+use anyhow::Result;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Wraps request handling, added with [`ServiceBuilder::layer`](super::ServiceBuilder::layer).
+/// A layer may answer the request itself or hand it on with [`Next::run`].
+pub trait Layer: Send + Sync + 'static {
+    fn call(&self, req: Request, next: Next) -> impl Future<Output = ()> + Send;
+}
+
+impl<F, Fut> Layer for F
+where
+    F: Fn(Request, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    fn call(&self, req: Request, next: Next) -> impl Future<Output = ()> + Send {
+        self(req, next)
+    }
+}
+
+type Handler = dyn Fn(Request) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync;
+
+/// The layers after this one and then the handler.
+#[derive(Clone)]
+pub struct Next(Arc<Handler>);
+
+impl Next {
+    pub(super) fn new<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self(Arc::new(move |req| Box::pin(handler(req))))
+    }
+    pub(super) fn wrap<L: Layer>(self, layer: Arc<L>) -> Self {
+        Self::new(move |req| {
+            let (layer, next) = (layer.clone(), self.clone());
+            async move { layer.call(req, next).await }
+        })
+    }
+    pub async fn run(self, req: Request) {
+        (self.0)(req).await
+    }
+}
+
+/// Prints every request and how long it took.
+pub struct Logging;
+
+impl Layer for Logging {
+    async fn call(&self, req: Request, next: Next) {
+        let (serial, request) = (req.serial.clone(), req.request.clone());
+        println!("Request => {serial} :: {request}");
+        let start = Instant::now();
+        next.run(req).await;
+        println!("Handled => {serial} :: {request} in {:?}", start.elapsed());
+    }
+}
+
+/// At most `limit` requests per client serial within each `per`, the rest get a `Rate Limited` error.
+pub struct RateLimit {
+    limit: u32,
+    per: Duration,
+    windows: Mutex<HashMap<Serial, (Instant, u32)>>,
+}
+
+impl RateLimit {
+    pub fn new(limit: u32, per: Duration) -> Self {
+        Self {
+            limit,
+            per,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+    fn allow(&self, serial: &Serial) -> bool {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        // clients pick their serial, windows of ones that went quiet would pile up
+        windows.retain(|_, (start, _)| now.duration_since(*start) < self.per);
+        let (start, count) = windows.entry(serial.clone()).or_insert((now, 0));
+        if now.duration_since(*start) >= self.per {
+            (*start, *count) = (now, 0);
+        }
+        *count += 1;
+        *count <= self.limit
+    }
+}
+
+impl Layer for RateLimit {
+    async fn call(&self, req: Request, next: Next) {
+        if self.allow(&req.serial) {
+            next.run(req).await;
+        } else {
+            let _ = req.reply(Reply::Error("Rate Limited")).await;
+        }
+    }
+}
+
+/// Answers with the error its check returns instead of handling the request.
+pub struct Validate<F>(pub F);
+
+impl<F> Layer for Validate<F>
+where
+    F: Fn(&Request) -> Result<()> + Send + Sync + 'static,
+{
+    async fn call(&self, req: Request, next: Next) {
+        match (self.0)(&req) {
+            Ok(()) => next.run(req).await,
+            Err(e) => {
+                let _ = req.reply(Reply::Error(format!("Invalid Request :: {e}"))).await;
+            }
+        }
+    }
+}
+
+/// How often each command ran and how long it took.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Timing {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+/// Times requests by the command they name, clones share the numbers.
+/// Requests that name none of the commands are timed together as `unknown`.
+#[derive(Clone)]
+pub struct Metrics {
+    timings: Arc<Mutex<HashMap<String, Timing>>>,
+    command: fn(&str) -> Option<String>,
+}
+
+impl Metrics {
+    /// Keyed by the commands of the enum `C`, so clients can't add entries by making names up.
+    pub fn of<C: DeserializeOwned>() -> Self {
+        Self {
+            timings: Arc::default(),
+            command: command::name::<C>,
+        }
+    }
+    pub fn snapshot(&self) -> HashMap<String, Timing> {
+        self.timings.lock().unwrap().clone()
+    }
+}
+
+impl Layer for Metrics {
+    async fn call(&self, req: Request, next: Next) {
+        let command = (self.command)(&req.request).unwrap_or_else(|| String::from("unknown"));
+        let start = Instant::now();
+        next.run(req).await;
+        let elapsed = start.elapsed();
+        let mut timings = self.timings.lock().unwrap();
+        let timing = timings.entry(command).or_default();
+        timing.count += 1;
+        timing.total += elapsed;
+        timing.max = timing.max.max(elapsed);
+    }
+}
@@ -0,0 +1,1350 @@
+mod command;
+mod connection;
+pub(crate) mod layer;
+mod name;
+
+pub use connection::{Backoff, ConnectionState};
+pub use name::{ServiceName, ServiceReq};
+
+use crate::client::ClientMapExt;
+use crate::config::Config;
+use crate::device::Serial;
+use crate::frame::{self, Payload};
+use crate::hub::{Hub, Lag};
+use crate::pending::{Pending, PendingMapExt};
+use crate::protocol::{Answer, Envelope, Kind, Protocol, VERSION, legacy_tag};
+use crate::schema::Schema;
+use crate::server::{Connection, ConnectionIO, ConnectionMap};
+use anyhow::{Error, Result};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::spawn_blocking;
+use connection::ServiceConnection;
+use layer::{Layer, Next};
+use std::time::Duration;
+use tokio::sync::{Semaphore, watch};
+use tokio::time::Instant;
+use tungstenite::Message;
+
+pub type ServiceMap = Arc<RwLock<HashMap<String, Instances>>>;
+
+/// Answered by the server itself with the registered services, no service may take the name.
+pub const SERVICES: &str = "services";
+
+/// Answered by the server itself, a request with `service#tag` or `#owner` unsubscribes like `-service#tag`.
+pub const UNSUBSCRIBE: &str = "unsubscribe";
+
+/// Why the server hung up on a service another one took the place of, it doesn't reconnect.
+pub const REPLACED: &str = "Service Replaced";
+
+static NEXT_INSTANCE: AtomicU64 = AtomicU64::new(1);
+
+/// What registering a name that is already taken does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Conflict {
+    /// Turn the newcomer away with `Service already registered`.
+    #[default]
+    Reject,
+    /// Close the registered service and take its place, subscribers stay even if the type changes.
+    Replace,
+    /// Keep both, requests go to each in turn. Only services of the same type pool.
+    Pool,
+}
+
+/// Which instance of a pool gets the next request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dispatch {
+    /// Each in turn.
+    #[default]
+    RoundRobin,
+    /// The one with the fewest requests still waiting on a reply.
+    LeastInFlight,
+}
+
+/// Every connection registered under one name and version, they share one hub.
+#[derive(Debug)]
+pub struct Instances {
+    name: ServiceName,
+    services: Vec<Service>,
+    next: AtomicUsize,
+}
+
+impl Instances {
+    /// Round robin over the instances.
+    fn pick(&self) -> Option<&Service> {
+        let at = self.next.fetch_add(1, Ordering::Relaxed);
+        self.services.get(at % self.services.len().max(1))
+    }
+}
+
+/// The registration a client means, exactly the one it named or else the newest version its range allows.
+fn resolve<'a>(map: &'a HashMap<String, Instances>, name: &str) -> Option<&'a Instances> {
+    if let Some(instances) = map.get(name) {
+        return Some(instances);
+    }
+    let req = ServiceReq::parse(name).ok()?;
+    map.values()
+        .filter(|instances| req.matches(&instances.name))
+        .max_by(|a, b| a.name.version.cmp(&b.name.version))
+}
+
+pub trait ServiceMapExt {
+    /// Registers `service` as it will be used and hands back the instances it replaced.
+    async fn insert(&self, service: Service, conflict: Conflict) -> Result<(Service, Vec<Service>)>;
+    async fn get(&self, name: impl Into<String>) -> Result<Service>;
+    async fn instance(&self, instance: u64) -> Result<Service>;
+    /// Every instance of the registration `name` resolves to.
+    async fn instances(&self, name: impl Into<String>) -> Vec<Service>;
+    /// True once the last instance of the name is gone.
+    async fn remove(&self, name: impl Into<String>, instance: u64) -> Result<bool>;
+    /// Every instance of every service.
+    async fn list(&self) -> Vec<Service>;
+}
+
+impl ServiceMapExt for ServiceMap {
+    async fn insert(&self, mut service: Service, conflict: Conflict) -> Result<(Service, Vec<Service>)> {
+        let this = self.clone();
+        spawn_blocking(move || {
+            let mut this = this.write().unwrap();
+            let name = ServiceName::parse(&service.name)?;
+            if name.name == SERVICES || name.name == UNSUBSCRIBE {
+                return Err(Error::msg("Service Name Reserved"));
+            }
+            // `mpd@2` and `mpd@2.0.0` are one registration
+            service.name = name.to_string();
+            let replaced = match this.get_mut(&service.name) {
+                Some(instances) if !instances.services.is_empty() => {
+                    let registered = &instances.services[0];
+                    let same_type = registered.service_type == service.service_type;
+                    let hub = registered.hub.clone();
+                    match conflict {
+                        Conflict::Pool if same_type => {
+                            service.hub = hub;
+                            Vec::new()
+                        }
+                        // subscribers stay, request services broadcast too
+                        Conflict::Replace => {
+                            service.hub = hub;
+                            std::mem::take(&mut instances.services)
+                        }
+                        Conflict::Pool | Conflict::Reject => {
+                            return Err(Error::msg("Service already registered"));
+                        }
+                    }
+                }
+                _ => Vec::new(),
+            };
+            this.entry(service.name.clone())
+                .or_insert_with(|| Instances {
+                    name,
+                    services: Vec::new(),
+                    next: AtomicUsize::new(0),
+                })
+                .services
+                .push(service.clone());
+            Ok((service, replaced))
+        })
+        .await?
+    }
+    async fn get(&self, name: impl Into<String>) -> Result<Service> {
+        let name = name.into();
+        let this = self.clone();
+        spawn_blocking(move || {
+            let this = this.read().unwrap();
+            let Some(service) = resolve(&this, &name).and_then(Instances::pick) else {
+                return Err(Error::msg("Invalid Service"));
+            };
+            Ok(service.clone())
+        })
+        .await?
+    }
+    async fn instance(&self, instance: u64) -> Result<Service> {
+        let this = self.clone();
+        spawn_blocking(move || {
+            this.read()
+                .unwrap()
+                .values()
+                .find_map(|instances| instances.services.iter().find(|s| s.instance == instance))
+                .cloned()
+                .ok_or_else(|| Error::msg("Invalid Service"))
+        })
+        .await?
+    }
+    async fn instances(&self, name: impl Into<String>) -> Vec<Service> {
+        let name = name.into();
+        let this = self.clone();
+        spawn_blocking(move || {
+            resolve(&this.read().unwrap(), &name)
+                .map(|instances| instances.services.clone())
+                .unwrap_or_default()
+        })
+        .await
+        .unwrap_or_default()
+    }
+    async fn remove(&self, name: impl Into<String>, instance: u64) -> Result<bool> {
+        let name = name.into();
+        let this = self.clone();
+        Ok(spawn_blocking(move || {
+            let mut this = this.write().unwrap();
+            let Some(instances) = this.get_mut(&name) else {
+                return false;
+            };
+            let before = instances.services.len();
+            instances.services.retain(|s| s.instance != instance);
+            let removed = instances.services.len() < before;
+            if instances.services.is_empty() {
+                this.remove(&name);
+            }
+            removed && !this.contains_key(&name)
+        })
+        .await?)
+    }
+    async fn list(&self) -> Vec<Service> {
+        let this = self.clone();
+        spawn_blocking(move || {
+            this.read()
+                .unwrap()
+                .values()
+                .flat_map(|instances| instances.services.iter().cloned())
+                .collect()
+        })
+        .await
+        .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceType {
+    Request,
+    Broadcast,
+}
+
+#[derive(Debug, Clone)]
+pub struct Service {
+    pub name: String,
+    /// Tells apart the connections registered under one name.
+    pub instance: u64,
+    pub service_type: ServiceType,
+    pub protocol: Protocol,
+    /// How long the service wants clients to wait before its requests time out.
+    pub timeout: Option<Duration>,
+    pub schema: Option<Schema>,
+    /// Asked for at registration, the server's `broadcast_lag` otherwise.
+    pub lag: Option<Lag>,
+    /// Where a broadcast service's messages fan out to its subscribers.
+    pub hub: Option<Arc<Hub>>,
+    connection: Connection,
+}
+
+impl ConnectionIO for Service {
+    async fn read(&mut self) -> Result<Message> {
+        self.connection.read().await
+    }
+    fn send(&self, msg: Message) -> Result<()> {
+        self.connection.send(msg)
+    }
+}
+
+impl Service {
+    /// `+name::type` for legacy services, a `register` envelope for json ones.
+    pub fn from_req(req: &str, connection: &Connection) -> Result<Option<Self>> {
+        if let Protocol::Json = Protocol::detect(req) {
+            let register = match Envelope::handshake(req, Kind::Register) {
+                Some(Ok(register)) => register,
+                Some(Err(e)) => {
+                    connection.send(Protocol::Json.error(None, e.to_string()))?;
+                    return Err(e);
+                }
+                None => return Ok(None),
+            };
+            let (Some(name), Some(service_type)) = (register.service, register.service_type) else {
+                connection.send(Protocol::Json.error(None, "Service Unspecified"))?;
+                return Err(Error::msg("Service Unspecified"));
+            };
+            return Ok(Some(Self {
+                name,
+                instance: NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed),
+                service_type,
+                protocol: Protocol::Json,
+                timeout: register.timeout.map(Duration::from_millis),
+                schema: register.schema,
+                lag: register.lag,
+                hub: None,
+                connection: connection.clone(),
+            }));
+        }
+        let Some(service_add) = req.strip_prefix("+") else {
+            return Ok(None);
+        };
+        // +name::type::timeout_ms::schema, the timeout may be left empty and both are optional
+        let mut req = service_add.splitn(4, "::");
+        let Some(name) = req.next() else {
+            connection.send(Message::text("Service Name Unspecified"))?;
+            return Ok(None);
+        };
+        let service_type = req.next();
+        let timeout = match req.next().filter(|t| !t.is_empty()).map(|t| t.parse::<u64>()) {
+            Some(Ok(ms)) => Some(Duration::from_millis(ms)),
+            Some(Err(_)) => {
+                connection.send(Message::text("!Invalid Timeout"))?;
+                return Err(Error::msg("Invalid Timeout"));
+            }
+            None => None,
+        };
+        let schema = match req.next().map(serde_json::from_str::<Schema>) {
+            Some(Ok(schema)) => Some(schema),
+            Some(Err(_)) => {
+                connection.send(Message::text("!Invalid Schema"))?;
+                return Err(Error::msg("Invalid Schema"));
+            }
+            None => None,
+        };
+        match service_type {
+            Some("request") => Ok(Some(Self {
+                name: String::from(name),
+                instance: NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed),
+                service_type: ServiceType::Request,
+                protocol: Protocol::Legacy,
+                timeout,
+                schema,
+                lag: None,
+                hub: None,
+                connection: connection.clone(),
+            })),
+            Some("broadcast") => Ok(Some(Self {
+                name: String::from(name),
+                instance: NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed),
+                service_type: ServiceType::Broadcast,
+                protocol: Protocol::Legacy,
+                timeout,
+                schema,
+                lag: None,
+                hub: None,
+                connection: connection.clone(),
+            })),
+            Some(_) => {
+                connection.send(Message::text("Invalid ServiceType"))?;
+                Err(Error::msg("Invalid ServiceType"))
+            }
+            None => {
+                connection.send(Message::text("ServiceType Unspecified"))?;
+                Err(Error::msg("ServiceType Unspecified"))
+            }
+        }
+    }
+    /// Closes the service's connection, another one took its name.
+    pub fn replaced(&self) {
+        self.connection.close(REPLACED);
+    }
+    /// Tells the service a request it got is no longer wanted.
+    pub fn cancel(&self, id: u64, pending: &Pending) -> Result<()> {
+        match self.protocol {
+            Protocol::Legacy => self.send(Message::text(format!(
+                "~{}@{}{}::{}",
+                pending.serial,
+                pending.client,
+                legacy_tag(pending.tag.as_deref()),
+                pending.request
+            ))),
+            Protocol::Json => self.send(
+                Envelope {
+                    id: Some(id),
+                    ..Envelope::new(Kind::Cancel)
+                }
+                .encode(),
+            ),
+        }
+    }
+    /// Forwards a client's request, json services only get the server's `id` to reply to.
+    pub fn request(&self, id: u64, pending: &Pending, payload: Payload) -> Result<()> {
+        match self.protocol {
+            Protocol::Legacy => self.send(frame::join(
+                &format!(
+                    "{}@{}{}",
+                    pending.serial,
+                    pending.client,
+                    legacy_tag(pending.tag.as_deref())
+                ),
+                payload,
+            )),
+            Protocol::Json => self.send(
+                Envelope {
+                    id: Some(id),
+                    serial: Some(pending.serial.clone()),
+                    tag: pending.tag.clone(),
+                    ..Envelope::new(Kind::Request)
+                }
+                .with_payload(payload)
+                .encode(),
+            ),
+        }
+    }
+}
+
+pub struct RequestHandler {
+    pub service: Service,
+    connection_map: ConnectionMap,
+}
+
+impl RequestHandler {
+    pub fn new(service: Service, connection_map: ConnectionMap) -> Result<Self> {
+        if let ServiceType::Request = service.service_type {
+            Ok(Self {
+                service,
+                connection_map,
+            })
+        } else {
+            Err(Error::msg("Not a Request Service"))
+        }
+    }
+    pub async fn handle(&mut self) -> Result<()> {
+        match self.read().await {
+            Ok(Message::Close(_)) => self.leave().await,
+            Ok(msg @ (Message::Text(_) | Message::Binary(_))) => {
+                let (pending, answer) = match self.service.protocol {
+                    Protocol::Legacy => {
+                        if let Some((topic, payload, retain)) = legacy_broadcast(&msg) {
+                            self.publish(topic.as_deref(), payload, retain);
+                            return Ok(());
+                        }
+                        match self.legacy_reply(&msg).await {
+                            Some(reply) => reply,
+                            None => {
+                                let _ = self.send("!Invalid Destination".into());
+                                return Ok(());
+                            }
+                        }
+                    }
+                    Protocol::Json => match Envelope::decode(&msg) {
+                        Ok(mut envelope) if envelope.kind == Kind::Broadcast => {
+                            if let Some(payload) = envelope.take_payload() {
+                                self.publish(envelope.topic.as_deref(), payload, envelope.retain);
+                            }
+                            return Ok(());
+                        }
+                        Ok(envelope) => match self.json_reply(envelope).await {
+                            Ok(reply) => reply,
+                            Err(e) => {
+                                let _ = self.send(Protocol::Json.error(None, e.to_string()));
+                                return Ok(());
+                            }
+                        },
+                        Err(e) => {
+                            let _ = self.send(Protocol::Json.error(None, e.to_string()));
+                            return Ok(());
+                        }
+                    },
+                };
+                let Some(client) = self
+                    .connection_map
+                    .client_map
+                    .get(pending.serial.clone(), pending.client)
+                    .await
+                else {
+                    let _ = self.send(self.service.protocol.error(None, "Invalid Destination"));
+                    return Ok(());
+                };
+                let _ = client.reply(&pending, answer);
+                Ok(())
+            }
+            Ok(msg) => {
+                eprintln!("{msg}");
+                Ok(())
+            }
+            Err(e) => {
+                let _ = self.leave().await;
+                Err(e)
+            }
+        }
+    }
+    /// Takes the instance out of the service map and hands what it still owes to the rest of its pool.
+    async fn leave(&self) -> Result<()> {
+        let removed = self
+            .connection_map
+            .service_map
+            .remove(&self.service.name, self.service.instance)
+            .await;
+        self.connection_map
+            .drain(&self.service.name, self.service.instance)
+            .await;
+        match removed {
+            Ok(last) => {
+                // the rest of a pool keeps the hub going
+                if last && let Some(hub) = &self.service.hub {
+                    hub.close();
+                }
+                Err(Error::msg("Connection closed"))
+            }
+            Err(_) => Err(Error::msg("Connection should but not")),
+        }
+    }
+}
+
+impl RequestHandler {
+    /// Request services may broadcast too, their subscribers are the ones of `&service` without a payload.
+    fn publish(&self, topic: Option<&str>, payload: Payload, retain: bool) {
+        if let Some(hub) = &self.service.hub {
+            hub.publish(topic, payload, retain);
+        }
+    }
+    /// unknown@1&req#tag::data, a leading `!` in the data marks an error.
+    /// Chunks of a stream start with `>`, the end of one is a chunk header without data.
+    async fn legacy_reply(&self, msg: &Message) -> Option<(Pending, Answer)> {
+        let (header, payload) = frame::split(msg)?;
+        let (streamed, header) = match header.strip_prefix(">") {
+            Some(header) => (true, header),
+            None => (false, header.as_str()),
+        };
+        let (serial, (id, (request, tag))) = header.split_once("@").and_then(|(s, d)| {
+            d.split_once("&").map(|(i, r)| {
+                (
+                    String::from(s),
+                    (
+                        i.parse::<u32>(),
+                        r.split_once("#")
+                            .map(|(r, t)| (String::from(r), Some(String::from(t))))
+                            .unwrap_or((String::from(r), None)),
+                    ),
+                )
+            })
+        })?;
+        let id = id.ok()?;
+        let answer = match (streamed, payload) {
+            (true, Some(payload)) => Answer::Chunk(payload),
+            (true, None) => Answer::End,
+            (false, Some(Payload::Text(text))) => match text.strip_prefix("!") {
+                Some(e) => Answer::Error(String::from(e)),
+                None => Answer::Reply(Payload::Text(text)),
+            },
+            (false, Some(binary)) => Answer::Reply(binary),
+            (false, None) => return None,
+        };
+        // replies the server has no record of still go where the header says
+        let fallback = Pending {
+            service: self.service.name.clone(),
+            instance: self.service.instance,
+            serial,
+            client: id,
+            id: None,
+            tag,
+            request: request.clone(),
+            payload: Payload::Text(request),
+            streamed: false,
+            timeout: Duration::ZERO,
+            deadline: Instant::now(),
+        };
+        let key = fallback.clone();
+        let matches = move |p: &Pending| {
+            p.instance == key.instance
+                && p.serial == key.serial
+                && p.client == key.client
+                && p.tag == key.tag
+                && p.request == key.request
+        };
+        let pending = if answer.is_final() {
+            self.connection_map.pending.take_oldest(matches).await
+        } else {
+            self.connection_map.pending.touch_oldest(matches).await
+        };
+        Some((pending.unwrap_or(fallback), answer))
+    }
+    async fn json_reply(&self, mut envelope: Envelope) -> Result<(Pending, Answer)> {
+        let answer = match envelope.kind {
+            Kind::Reply => Answer::Reply(envelope.take_payload().unwrap_or(Payload::Text(String::new()))),
+            Kind::Error => Answer::Error(envelope.error.take().unwrap_or_default()),
+            Kind::Chunk => Answer::Chunk(envelope.take_payload().unwrap_or(Payload::Text(String::new()))),
+            Kind::End => Answer::End,
+            _ => return Err(Error::msg("Invalid Reply")),
+        };
+        let Some(id) = envelope.id else {
+            return Err(Error::msg("Request Id Unspecified"));
+        };
+        let pending = if answer.is_final() {
+            self.connection_map.pending.remove(id).await
+        } else {
+            self.connection_map.pending.touch(id).await
+        };
+        let Some(pending) = pending else {
+            return Err(Error::msg(format!("Unknown Request {id}")));
+        };
+        Ok((pending, answer))
+    }
+}
+
+impl ConnectionIO for RequestHandler {
+    async fn read(&mut self) -> Result<Message> {
+        self.service.read().await
+    }
+    fn send(&self, msg: Message) -> Result<()> {
+        self.service.send(msg)
+    }
+}
+
+/// `@topic::payload`, or `=topic::payload` to retain it, as topic, payload and whether to retain.
+fn legacy_broadcast(msg: &Message) -> Option<(Option<String>, Payload, bool)> {
+    let (header, payload) = frame::split(msg)?;
+    let retain = match header.chars().next()? {
+        '@' => false,
+        '=' => true,
+        _ => return None,
+    };
+    let topic = Some(String::from(&header[1..])).filter(|t| !t.is_empty());
+    Some((topic, payload?, retain))
+}
+
+/// Reads a broadcast service and publishes to its hub, the only reader of that connection.
+pub struct BroadcastHandler {
+    service: Service,
+    hub: Arc<Hub>,
+    connection_map: ConnectionMap,
+}
+
+impl BroadcastHandler {
+    pub fn new(service: Service, connection_map: ConnectionMap) -> Result<Self> {
+        match (&service.service_type, service.hub.clone()) {
+            (ServiceType::Broadcast, Some(hub)) => Ok(Self {
+                service,
+                hub,
+                connection_map,
+            }),
+            _ => Err(Error::msg("Not a Broadcast Service")),
+        }
+    }
+    pub async fn handle(&mut self) -> Result<()> {
+        match self.read().await {
+            Ok(Message::Close(_f)) => {
+                match self
+                    .connection_map
+                    .service_map
+                    .remove(&self.service.name, self.service.instance)
+                    .await
+                {
+                    Ok(last) => {
+                        if last {
+                            self.hub.close();
+                        }
+                        Err(Error::msg("Connection closed"))
+                    }
+                    Err(_) => Err(Error::msg("Connection should but not")),
+                }
+            }
+            Ok(msg @ (Message::Text(_) | Message::Binary(_))) => {
+                let (topic, payload, retain) = match (self.service.protocol, msg) {
+                    (Protocol::Legacy, msg) => match legacy_broadcast(&msg) {
+                        Some(broadcast) => broadcast,
+                        None => match msg {
+                            Message::Text(text) => (None, Payload::Text(String::from(text.as_str())), false),
+                            Message::Binary(bytes) => (None, Payload::Binary(bytes), false),
+                            _ => return Ok(()),
+                        },
+                    },
+                    (Protocol::Json, msg) => match Envelope::decode(&msg) {
+                        Ok(mut envelope) => match envelope.take_payload() {
+                            Some(payload) => (envelope.topic, payload, envelope.retain),
+                            None => return Ok(()),
+                        },
+                        Err(_) => return Ok(()),
+                    },
+                };
+                self.hub.publish(topic.as_deref(), payload, retain);
+                Ok(())
+            }
+            Ok(_msg) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl ConnectionIO for BroadcastHandler {
+    async fn read(&mut self) -> Result<Message> {
+        self.service.read().await
+    }
+    fn send(&self, msg: Message) -> Result<()> {
+        self.service.send(msg)
+    }
+}
+
+pub struct RequestService {
+    connection: ServiceConnection,
+    protocol: Protocol,
+    concurrency: usize,
+    layers: Vec<Wrap>,
+    cancels: HashMap<String, Vec<watch::Sender<bool>>>,
+}
+
+/// A layer waiting for the rest of the chain, see [`ServiceBuilder::layer`].
+type Wrap = Box<dyn FnOnce(Next) -> Next + Send>;
+
+pub struct BroadcastService {
+    connection: ServiceConnection,
+    publisher: Publisher,
+}
+
+/// Broadcasts over a service's connection, see [`RequestService::publisher`].
+#[derive(Clone)]
+pub struct Publisher {
+    sender: UnboundedSender<Message>,
+    protocol: Protocol,
+}
+
+/// Where a reply goes, legacy services address the client themselves.
+enum Route {
+    Legacy { id: u32 },
+    Json { id: u64 },
+}
+
+/// Set once the client cancels the request, long running handlers can check or await it.
+#[derive(Clone)]
+pub struct Cancellation(watch::Receiver<bool>);
+
+impl Cancellation {
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+    /// Resolves once cancelled, never if the request is dropped first.
+    pub async fn cancelled(&self) {
+        let mut cancelled = self.0.clone();
+        if cancelled.wait_for(|c| *c).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+pub struct Request {
+    reply_channel: UnboundedSender<Message>,
+    route: Route,
+    cancellation: Cancellation,
+    pub serial: Serial,
+    pub tag: Option<String>,
+    pub request: String,
+    /// Raw payload of a binary request, `request` is empty then.
+    pub bytes: Option<Bytes>,
+}
+
+pub enum Reply<T>
+where
+    T: Into<String>,
+{
+    Text(T),
+    Binary(Bytes),
+    Error(T),
+}
+
+pub enum BroadcastMessage {
+    Text(String),
+    Binary(Bytes),
+}
+
+impl From<BroadcastMessage> for Payload {
+    fn from(message: BroadcastMessage) -> Self {
+        match message {
+            BroadcastMessage::Text(text) => Payload::Text(text),
+            BroadcastMessage::Binary(bytes) => Payload::Binary(bytes),
+        }
+    }
+}
+
+/// Implemented by `#[zeitop::service]` for an impl block, its async methods are the commands.
+pub trait ServiceHandler: Send + Sync + 'static {
+    type Command: DeserializeOwned + Send + 'static;
+    const NAME: &'static str;
+    fn schema() -> Schema;
+    fn dispatch(self: Arc<Self>, command: Self::Command, req: Request) -> impl Future<Output = ()> + Send;
+    /// Named and described by the attribute, `.url(..)` and the rest still apply.
+    fn builder() -> ServiceBuilder<RequestService>
+    where
+        Self: Sized,
+    {
+        RequestService::builder(Self::NAME).schema(Self::schema())
+    }
+}
+
+/// Paths `#[zeitop::service]` expands to.
+#[doc(hidden)]
+pub mod __private {
+    pub use super::{Request, ServiceHandler};
+    pub use crate::schema::Schema;
+    pub use serde;
+}
+
+/// Builds a service connection, the url defaults to [`Config::discover_url`].
+pub struct ServiceBuilder<S> {
+    name: String,
+    url: Option<String>,
+    reconnect: Option<Backoff>,
+    protocol: Protocol,
+    timeout: Option<Duration>,
+    schema: Option<Schema>,
+    lag: Option<Lag>,
+    concurrency: usize,
+    layers: Vec<Wrap>,
+    service: PhantomData<S>,
+}
+
+impl<S> ServiceBuilder<S> {
+    fn new(name: &str) -> Self {
+        Self {
+            name: String::from(name),
+            url: None,
+            reconnect: Some(Backoff::default()),
+            protocol: Protocol::default(),
+            timeout: None,
+            schema: None,
+            lag: None,
+            concurrency: 8,
+            layers: Vec::new(),
+            service: PhantomData,
+        }
+    }
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+    /// Delay before the first reconnect attempt, doubled on each failure up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.reconnect = Some(Backoff { initial, max });
+        self
+    }
+    /// Close for good on the first disconnect instead of reconnecting.
+    pub fn no_reconnect(mut self) -> Self {
+        self.reconnect = None;
+        self
+    }
+    /// Json unless talking to a server that only knows the legacy strings.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+    /// Published at registration, clients read it back from the `services` endpoint.
+    pub fn schema(mut self, schema: Schema) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+    /// What the server does with subscribers that fall behind, json only, legacy services get the server's default.
+    pub fn lag(mut self, lag: Lag) -> Self {
+        self.lag = Some(lag);
+        self
+    }
+    async fn register(self, service_type: ServiceType) -> Result<ServiceConnection> {
+        let url = self.url.unwrap_or_else(Config::discover_url);
+        let registration = match self.protocol {
+            Protocol::Legacy => {
+                let service_type = match service_type {
+                    ServiceType::Request => "request",
+                    ServiceType::Broadcast => "broadcast",
+                };
+                let timeout = self.timeout.map(|t| t.as_millis().to_string());
+                match (timeout, &self.schema) {
+                    (timeout, Some(schema)) => format!(
+                        "+{}::{service_type}::{}::{}",
+                        self.name,
+                        timeout.unwrap_or_default(),
+                        serde_json::to_string(schema)?
+                    ),
+                    (Some(timeout), None) => format!("+{}::{service_type}::{timeout}", self.name),
+                    (None, None) => format!("+{}::{service_type}", self.name),
+                }
+            }
+            Protocol::Json => Envelope {
+                version: Some(VERSION),
+                service: Some(self.name),
+                service_type: Some(service_type),
+                timeout: self.timeout.map(|t| t.as_millis() as u64),
+                schema: self.schema,
+                lag: self.lag,
+                ..Envelope::new(Kind::Register)
+            }
+            .encode()
+            .into_text()?
+            .to_string(),
+        };
+        ServiceConnection::connect(url, registration, self.reconnect).await
+    }
+}
+
+impl ServiceBuilder<RequestService> {
+    /// How long clients wait on this service before the server answers with a timeout error,
+    /// the server's `request_timeout` otherwise.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    /// How many requests [`RequestService::serve`] handles at once.
+    pub fn concurrency(mut self, limit: usize) -> Self {
+        self.concurrency = limit.max(1);
+        self
+    }
+    /// Runs `layer` around every request the handler gets, layers added first run first.
+    pub fn layer<L: Layer>(mut self, layer: L) -> Self {
+        let layer = Arc::new(layer);
+        self.layers.push(Box::new(move |next: Next| next.wrap(layer)));
+        self
+    }
+    pub async fn connect(mut self) -> Result<RequestService> {
+        let (protocol, concurrency) = (self.protocol, self.concurrency);
+        let layers = std::mem::take(&mut self.layers);
+        Ok(RequestService {
+            connection: self.register(ServiceType::Request).await?,
+            protocol,
+            concurrency,
+            layers,
+            cancels: HashMap::new(),
+        })
+    }
+}
+
+impl ServiceBuilder<BroadcastService> {
+    pub async fn connect(self) -> Result<BroadcastService> {
+        let protocol = self.protocol;
+        let connection = self.register(ServiceType::Broadcast).await?;
+        Ok(BroadcastService {
+            publisher: Publisher {
+                sender: connection.sender.clone(),
+                protocol,
+            },
+            connection,
+        })
+    }
+}
+
+impl RequestService {
+    pub async fn new(name: &str) -> Result<Self> {
+        Self::builder(name).connect().await
+    }
+    pub fn builder(name: &str) -> ServiceBuilder<Self> {
+        ServiceBuilder::new(name)
+    }
+    pub fn state(&self) -> watch::Receiver<ConnectionState> {
+        self.connection.state()
+    }
+    /// Broadcasts from this same connection, clients subscribe with `&service` and no payload.
+    /// The connection closes once the service is dropped, publishing after that fails.
+    pub fn publisher(&self) -> Publisher {
+        Publisher {
+            sender: self.connection.sender.clone(),
+            protocol: self.protocol,
+        }
+    }
+    /// Runs `handler` on its own task for every request, at most `concurrency` at a time.
+    /// Each [`Request`] carries its own reply address so replies can finish in any order.
+    pub async fn serve<F, Fut>(mut self, handler: F) -> Result<()>
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut handler = Next::new(handler);
+        for wrap in std::mem::take(&mut self.layers).into_iter().rev() {
+            handler = wrap(handler);
+        }
+        let permits = Arc::new(Semaphore::new(self.concurrency));
+        while let Some(req) = self.next().await {
+            let req = match req {
+                Ok(req) => req,
+                Err(e) => {
+                    eprintln!("{e}");
+                    continue;
+                }
+            };
+            let permit = permits.clone().acquire_owned().await?;
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                handler.run(req).await;
+                drop(permit);
+            });
+        }
+        Ok(())
+    }
+    /// Answers every request with the method of `handler` it names, see [`ServiceHandler`].
+    pub async fn serve_handler<H: ServiceHandler>(self, handler: H) -> Result<()> {
+        let handler = Arc::new(handler);
+        self.serve_commands(move |command, req| handler.clone().dispatch(command, req))
+            .await
+    }
+    /// Like [`serve`](Self::serve) with every request parsed into the command enum `C` first,
+    /// requests that don't parse are answered with an `Invalid Request` error without reaching `handler`.
+    /// A command is the variant name followed by its fields, or the enum as json, see [`Schema::of`] to publish them.
+    pub async fn serve_commands<C, F, Fut>(self, handler: F) -> Result<()>
+    where
+        C: DeserializeOwned + Send + 'static,
+        F: Fn(C, Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.serve(move |req| {
+            let handler = handler.clone();
+            async move {
+                match req.command::<C>() {
+                    Ok(command) => handler(command, req).await,
+                    Err(e) => {
+                        let _ = req.reply(Reply::Error(format!("Invalid Request :: {e}"))).await;
+                    }
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// Ends only once the connection is closed for good, malformed frames come through as errors.
+impl Stream for RequestService {
+    type Item = Result<Request>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            return match ready!(self.connection.poll_read(cx)) {
+                Some(msg @ (Message::Text(_) | Message::Binary(_))) => {
+                    if let Some(key) = cancel_key(&msg, self.protocol) {
+                        for cancel in self.cancels.remove(&key).unwrap_or_default() {
+                            let _ = cancel.send(true);
+                        }
+                        continue;
+                    }
+                    let (cancel, cancellation) = watch::channel(false);
+                    let req = Request::parse(
+                        &msg,
+                        self.protocol,
+                        self.connection.sender.clone(),
+                        Cancellation(cancellation),
+                    );
+                    if let Ok(req) = &req {
+                        // dropped requests have nothing left to cancel
+                        self.cancels.retain(|_, cancels| {
+                            cancels.retain(|c| c.receiver_count() > 0);
+                            !cancels.is_empty()
+                        });
+                        self.cancels.entry(req.key()).or_default().push(cancel);
+                    }
+                    Poll::Ready(Some(req))
+                }
+                Some(_) => continue,
+                None => Poll::Ready(None),
+            };
+        }
+    }
+}
+
+impl Request {
+    /// serial@id#tag::request for legacy services, the request is raw bytes in a binary frame
+    fn parse(
+        msg: &Message,
+        protocol: Protocol,
+        reply_channel: UnboundedSender<Message>,
+        cancellation: Cancellation,
+    ) -> Result<Self> {
+        let (route, serial, tag, payload) = match protocol {
+            Protocol::Legacy => {
+                let Some((header, payload)) = frame::split(msg) else {
+                    return Err(Error::msg("Invalid Request Header"));
+                };
+                let Some((serial, (Ok(id), tag))) = header.split_once("@").map(|(s, i)| {
+                    (
+                        s,
+                        i.split_once("#")
+                            .map(|(i, t)| (i.parse::<u32>(), Some(String::from(t))))
+                            .unwrap_or((i.parse::<u32>(), None)),
+                    )
+                }) else {
+                    return Err(Error::msg("Invalid Request Header"));
+                };
+                (Route::Legacy { id }, String::from(serial), tag, payload)
+            }
+            Protocol::Json => {
+                let mut envelope = Envelope::decode(msg)?;
+                let (Kind::Request, Some(id)) = (envelope.kind, envelope.id) else {
+                    return Err(Error::msg("Invalid Request Header"));
+                };
+                let payload = envelope.take_payload();
+                (
+                    Route::Json { id },
+                    envelope.serial.unwrap_or_default(),
+                    envelope.tag,
+                    payload,
+                )
+            }
+        };
+        let (request, bytes) = match payload {
+            Some(Payload::Text(req)) => (req, None),
+            Some(Payload::Binary(bytes)) => (String::new(), Some(bytes)),
+            None => return Err(Error::msg("Request Unspecified")),
+        };
+        Ok(Self {
+            reply_channel,
+            route,
+            cancellation,
+            serial,
+            tag,
+            request,
+            bytes,
+        })
+    }
+    /// Matches the cancel the server sends for this request.
+    fn key(&self) -> String {
+        match self.route {
+            Route::Legacy { id } => format!(
+                "{}@{id}{}::{}",
+                self.serial,
+                legacy_tag(self.tag.as_deref()),
+                self.request
+            ),
+            Route::Json { id } => id.to_string(),
+        }
+    }
+    pub fn cancellation(&self) -> Cancellation {
+        self.cancellation.clone()
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+    pub async fn reply<T>(&self, reply: Reply<T>) -> Result<()>
+    where
+        T: Into<String>,
+    {
+        self.answer(match reply {
+            Reply::Text(reply) => Answer::Reply(Payload::Text(reply.into())),
+            Reply::Binary(bytes) => Answer::Reply(Payload::Binary(bytes)),
+            Reply::Error(e) => {
+                let e = e.into();
+                println!("heh => {e}");
+                Answer::Error(e)
+            }
+        })
+    }
+    /// Reads the request as a command of the enum `C`, see [`RequestService::serve_commands`].
+    pub fn command<C: DeserializeOwned>(&self) -> Result<C> {
+        command::parse(&self.request)
+    }
+    /// Replies with `result` as json, strings as they are so plain text replies stay plain.
+    pub async fn respond<T: Serialize>(&self, result: Result<T>) -> Result<()> {
+        let reply = match result.and_then(|value| Ok(serde_json::to_value(value)?)) {
+            Ok(serde_json::Value::String(text)) => Reply::Text(text),
+            Ok(value) => Reply::Text(value.to_string()),
+            Err(e) => Reply::Error(e.to_string()),
+        };
+        self.reply(reply).await
+    }
+    /// Replies in parts, the client knows the answer is complete once the stream is finished or dropped.
+    pub fn stream(&self) -> ReplyStream<'_> {
+        ReplyStream {
+            request: self,
+            finished: false,
+        }
+    }
+    fn answer(&self, answer: Answer) -> Result<()> {
+        let msg = match self.route {
+            Route::Legacy { id } => {
+                let header = format!(
+                    "{}@{id}&{}{}",
+                    self.serial,
+                    self.request,
+                    legacy_tag(self.tag.as_deref())
+                );
+                match answer {
+                    Answer::Reply(payload) => frame::join(&header, payload),
+                    Answer::Error(e) => frame::join(&header, Payload::Text(format!("!{e}"))),
+                    Answer::Chunk(payload) => frame::join(&format!(">{header}"), payload),
+                    Answer::End => Message::text(format!(">{header}")),
+                }
+            }
+            Route::Json { id } => {
+                let envelope = Envelope {
+                    id: Some(id),
+                    ..Envelope::new(Kind::Reply)
+                };
+                match answer {
+                    Answer::Reply(payload) => envelope.with_payload(payload),
+                    Answer::Error(e) => Envelope::error(Some(id), e),
+                    Answer::Chunk(payload) => Envelope {
+                        kind: Kind::Chunk,
+                        ..envelope
+                    }
+                    .with_payload(payload),
+                    Answer::End => Envelope {
+                        kind: Kind::End,
+                        ..envelope
+                    },
+                }
+                .encode()
+            }
+        };
+        self.reply_channel.send(msg)?;
+        Ok(())
+    }
+}
+
+/// Sends a reply in chunks, each one also keeps the request from timing out.
+pub struct ReplyStream<'a> {
+    request: &'a Request,
+    finished: bool,
+}
+
+impl ReplyStream<'_> {
+    /// An error ends the stream.
+    pub async fn send<T>(&mut self, chunk: Reply<T>) -> Result<()>
+    where
+        T: Into<String>,
+    {
+        if self.finished {
+            return Err(Error::msg("Stream already finished"));
+        }
+        self.request.answer(match chunk {
+            Reply::Text(text) => Answer::Chunk(Payload::Text(text.into())),
+            Reply::Binary(bytes) => Answer::Chunk(Payload::Binary(bytes)),
+            Reply::Error(e) => {
+                self.finished = true;
+                Answer::Error(e.into())
+            }
+        })
+    }
+    pub async fn finish(mut self) -> Result<()> {
+        self.finished = true;
+        self.request.answer(Answer::End)
+    }
+}
+
+impl Drop for ReplyStream<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.request.answer(Answer::End);
+        }
+    }
+}
+
+/// ~serial@id#tag::request for legacy services, the key of the request to cancel.
+fn cancel_key(msg: &Message, protocol: Protocol) -> Option<String> {
+    match protocol {
+        Protocol::Legacy => match msg {
+            Message::Text(text) => text.as_str().strip_prefix("~").map(String::from),
+            _ => None,
+        },
+        Protocol::Json => match Envelope::decode(msg).ok()? {
+            Envelope {
+                kind: Kind::Cancel,
+                id: Some(id),
+                ..
+            } => Some(id.to_string()),
+            _ => None,
+        },
+    }
+}
+
+impl BroadcastService {
+    pub async fn new(name: &str) -> Result<Self> {
+        Self::builder(name).connect().await
+    }
+    pub fn builder(name: &str) -> ServiceBuilder<Self> {
+        ServiceBuilder::new(name)
+    }
+    pub fn state(&self) -> watch::Receiver<ConnectionState> {
+        self.connection.state()
+    }
+    pub async fn broadcast(&self, message: BroadcastMessage) -> Result<()> {
+        self.publisher.broadcast(message).await
+    }
+    /// See [`Publisher::publish`].
+    pub async fn publish(&self, topic: &str, message: BroadcastMessage) -> Result<()> {
+        self.publisher.publish(topic, message).await
+    }
+    /// See [`Publisher::retain`].
+    pub async fn retain(&self, topic: &str, message: BroadcastMessage) -> Result<()> {
+        self.publisher.retain(topic, message).await
+    }
+    pub fn publisher(&self) -> Publisher {
+        self.publisher.clone()
+    }
+}
+
+impl Publisher {
+    fn send(&self, topic: Option<&str>, payload: Payload, retain: bool) -> Result<()> {
+        Ok(self.sender.send(match (self.protocol, topic, payload) {
+            // the prefix even without a topic, request services would take the message for a reply
+            (Protocol::Legacy, topic, payload) => frame::join(
+                &format!("{}{}", if retain { "=" } else { "@" }, topic.unwrap_or_default()),
+                payload,
+            ),
+            (Protocol::Json, topic, payload) => Envelope {
+                topic: topic.map(String::from),
+                retain,
+                ..Envelope::new(Kind::Broadcast)
+            }
+            .with_payload(payload)
+            .encode(),
+        })?)
+    }
+    pub async fn broadcast(&self, message: BroadcastMessage) -> Result<()> {
+        self.send(None, message.into(), false)
+    }
+    /// Broadcasts under a `/` separated topic, only subscribers whose pattern matches it get the message.
+    pub async fn publish(&self, topic: &str, message: BroadcastMessage) -> Result<()> {
+        self.send(Some(topic), message.into(), false)
+    }
+    /// Publishes and keeps the message as the topic's current value, clients subscribing later get it first.
+    /// An empty text message forgets the value.
+    pub async fn retain(&self, topic: &str, message: BroadcastMessage) -> Result<()> {
+        self.send(Some(topic), message.into(), true)
+    }
+}
+
+// TODO :: send request@service::data or somthing of that sort
+//      :: point is to include more infomations for client to know
+//      :: and maybe let client tag their own info for idntification
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(registrations: &[&str]) -> HashMap<String, Instances> {
+        registrations
+            .iter()
+            .map(|registration| {
+                let name = ServiceName::parse(registration).unwrap();
+                let instances = Instances {
+                    name: name.clone(),
+                    services: Vec::new(),
+                    next: AtomicUsize::new(0),
+                };
+                (name.to_string(), instances)
+            })
+            .collect()
+    }
+
+    fn resolved(map: &HashMap<String, Instances>, name: &str) -> Option<String> {
+        resolve(map, name).map(|instances| instances.name.to_string())
+    }
+
+    #[test]
+    fn exact_names_win() {
+        let map = map(&["mpd", "mpd@1.0.0", "mpd@2.1.0"]);
+        assert_eq!(resolved(&map, "mpd").as_deref(), Some("mpd"));
+        assert_eq!(resolved(&map, "mpd@1.0.0").as_deref(), Some("mpd@1.0.0"));
+    }
+
+    #[test]
+    fn ranges_take_the_newest_match() {
+        let map = map(&["mpd@1.0.0", "mpd@2.0.0", "mpd@2.1.0", "z3phyrl/mpd@9.0.0"]);
+        assert_eq!(resolved(&map, "mpd").as_deref(), Some("mpd@2.1.0"));
+        assert_eq!(resolved(&map, "mpd@2").as_deref(), Some("mpd@2.1.0"));
+        assert_eq!(resolved(&map, "mpd@<2").as_deref(), Some("mpd@1.0.0"));
+    }
+
+    #[test]
+    fn no_instance_matches_the_version() {
+        let map = map(&["mpd", "mpd@1.0.0", "mpd@2.1.0"]);
+        assert_eq!(resolved(&map, "mpd@3"), None);
+        assert_eq!(resolved(&map, "mpd@>2.1.0"), None);
+        assert_eq!(resolved(&map, "obs"), None);
+        assert_eq!(resolved(&map, "mpd@not a range"), None);
+    }
+}
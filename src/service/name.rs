@@ -0,0 +1,144 @@
+use anyhow::{Error, Result};
+use semver::{Version, VersionReq};
+use std::fmt;
+
+/// A registration, `name` or `vendor/name` with an optional `@version`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceName {
+    pub name: String,
+    pub version: Option<Version>,
+}
+
+impl ServiceName {
+    /// `mpd@2` stands for `mpd@2.0.0`.
+    pub fn parse(registration: &str) -> Result<Self> {
+        let (name, version) = split(registration)?;
+        let version = version
+            .map(|v| {
+                [v.to_owned(), format!("{v}.0"), format!("{v}.0.0")]
+                    .iter()
+                    .find_map(|v| Version::parse(v).ok())
+                    .ok_or_else(|| Error::msg("Invalid Service Version"))
+            })
+            .transpose()?;
+        Ok(Self { name, version })
+    }
+}
+
+impl fmt::Display for ServiceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{}@{version}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// What a client asks for, `mpd`, `mpd@2` or `vendor/mpd@>=1.2, <3`.
+#[derive(Debug, Clone)]
+pub struct ServiceReq {
+    pub name: String,
+    pub version: Option<VersionReq>,
+}
+
+impl ServiceReq {
+    /// A bare version like `2` takes any compatible one, as in `^2`.
+    pub fn parse(req: &str) -> Result<Self> {
+        let (name, version) = split(req)?;
+        let version = version
+            .map(|v| VersionReq::parse(v).map_err(|_| Error::msg("Invalid Service Version")))
+            .transpose()?;
+        Ok(Self { name, version })
+    }
+    /// Without a version every registration of the name does, unversioned ones only match that.
+    pub fn matches(&self, registration: &ServiceName) -> bool {
+        self.name == registration.name
+            && match (&self.version, &registration.version) {
+                (None, _) => true,
+                (Some(req), Some(version)) => req.matches(version),
+                (Some(_), None) => false,
+            }
+    }
+}
+
+fn split(s: &str) -> Result<(String, Option<&str>)> {
+    let (name, version) = match s.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (s, None),
+    };
+    let valid = name.split('/').all(|part| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    });
+    if !valid {
+        return Err(Error::msg("Invalid Service Name"));
+    }
+    Ok((String::from(name), version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(s: &str) -> ServiceName {
+        ServiceName::parse(s).unwrap()
+    }
+
+    #[test]
+    fn fills_in_short_versions() {
+        assert_eq!(registration("mpd@2").version, Some(Version::new(2, 0, 0)));
+        assert_eq!(registration("mpd@2.1").version, Some(Version::new(2, 1, 0)));
+        assert_eq!(registration("mpd@2.1.3").to_string(), "mpd@2.1.3");
+        assert_eq!(registration("mpd@2").to_string(), "mpd@2.0.0");
+    }
+
+    #[test]
+    fn names_may_have_a_vendor() {
+        let name = registration("z3phyrl/mpd@1.0.0-beta.1");
+        assert_eq!(name.name, "z3phyrl/mpd");
+        assert_eq!(name.version.unwrap().pre.as_str(), "beta.1");
+        assert_eq!(registration("mpd").version, None);
+    }
+
+    #[test]
+    fn rejects_invalid_names_and_versions() {
+        for invalid in ["", "@1", "/mpd", "mpd/", "a//b", "mp d", "mpd#ui", "mpd@", "mpd@x", "mpd@>=1"] {
+            assert!(ServiceName::parse(invalid).is_err(), "{invalid}");
+        }
+        assert!(ServiceReq::parse("mpd@not a range").is_err());
+    }
+
+    #[test]
+    fn bare_versions_are_caret_ranges() {
+        let req = ServiceReq::parse("mpd@2").unwrap();
+        assert!(req.matches(&registration("mpd@2.0.0")));
+        assert!(req.matches(&registration("mpd@2.9.1")));
+        assert!(!req.matches(&registration("mpd@3.0.0")));
+        assert!(!req.matches(&registration("mpd@1.9.0")));
+    }
+
+    #[test]
+    fn ranges() {
+        let req = ServiceReq::parse("z3phyrl/mpd@>=1.2, <3").unwrap();
+        assert!(req.matches(&registration("z3phyrl/mpd@1.2.0")));
+        assert!(req.matches(&registration("z3phyrl/mpd@2.5.0")));
+        assert!(!req.matches(&registration("z3phyrl/mpd@3.0.0")));
+        assert!(!req.matches(&registration("mpd@2.0.0")));
+    }
+
+    #[test]
+    fn unversioned_requests_take_any_registration() {
+        let req = ServiceReq::parse("mpd").unwrap();
+        assert!(req.matches(&registration("mpd")));
+        assert!(req.matches(&registration("mpd@4.0.0")));
+        assert!(!req.matches(&registration("mpd2")));
+    }
+
+    #[test]
+    fn versioned_requests_skip_unversioned_registrations() {
+        let req = ServiceReq::parse("mpd@1").unwrap();
+        assert!(!req.matches(&registration("mpd")));
+    }
+}